@@ -9,7 +9,7 @@
 //! new memory settings.
 
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
 
@@ -29,6 +29,18 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
+    // Embed the public key firmware updates are verified with. It is created
+    // with `tools/sign_firmware.py keygen`, FIRMWARE_PUBLIC_KEY can point to
+    // another key file.
+    let key_path = env::var("FIRMWARE_PUBLIC_KEY").unwrap_or_else(|_| "firmware_key.pub".into());
+    let key = fs::read(&key_path).unwrap_or_else(|e| {
+        panic!("cannot read firmware public key {key_path}: {e}, create one with tools/sign_firmware.py keygen")
+    });
+    assert_eq!(key.len(), 32, "{key_path} is not a raw ed25519 public key");
+    fs::write(out.join("firmware_key.pub"), key).unwrap();
+    println!("cargo:rerun-if-env-changed=FIRMWARE_PUBLIC_KEY");
+    println!("cargo:rerun-if-changed={key_path}");
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
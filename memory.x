@@ -1,5 +1,17 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    /* the bootloader lives in 0x10000100..0x10006000, see bootloader/memory.x */
+    BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+    FLASH : ORIGIN = 0x10007000, LENGTH = 960K
+    /* update partition, one page larger than FLASH for the swap */
+    DFU : ORIGIN = 0x100F7000, LENGTH = 964K
+    /* persistent records, one 4K sector per slot, see storage.rs */
+    STORAGE : ORIGIN = 0x101FC000, LENGTH = 16K
     RAM   : ORIGIN = 0x20000000, LENGTH = 256K
-}
\ No newline at end of file
+}
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
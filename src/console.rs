@@ -0,0 +1,250 @@
+use core::fmt::{self, Write};
+
+use cyw43::NetDriver;
+use embassy_net::Stack;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_usb::class::cdc_acm::CdcAcmClass;
+use embassy_usb::driver::EndpointError;
+use embassy_usb::UsbDevice;
+
+use crate::state::{self, FilterState, WifiCredentials};
+use crate::storage::Storage;
+use crate::{netlog, network, reset, STATE};
+
+pub type UsbDriver = Driver<'static, USB>;
+
+const PACKET_SIZE: usize = 64;
+const LINE_SIZE: usize = 128;
+
+const HELP: &str = "commands:\r
+  state                       show the filter state\r
+  config                      show the config\r
+  set start|end <value>       set the fill thresholds\r
+  force idle|clean|fill <ms>  force a state like the server does\r
+  wifi <ssid> <password>      store wifi credentials and join again\r
+  network                     show the network status\r
+  reboot                      close the valves and restart\r
+";
+
+#[embassy_executor::task]
+pub async fn usb_task(mut usb: UsbDevice<'static, UsbDriver>) -> ! {
+    usb.run().await
+}
+
+// line based shell on the usb serial port
+#[embassy_executor::task]
+pub async fn console_task(
+    mut class: CdcAcmClass<'static, UsbDriver>,
+    stack: &'static Stack<NetDriver<'static>>,
+    storage: Storage,
+) -> ! {
+    loop {
+        class.wait_connection().await;
+        netlog::info!("console connected");
+        let _ = session(&mut class, stack, storage).await;
+        netlog::info!("console disconnected");
+    }
+}
+
+async fn session(
+    class: &mut CdcAcmClass<'static, UsbDriver>,
+    stack: &'static Stack<NetDriver<'static>>,
+    storage: Storage,
+) -> Result<(), EndpointError> {
+    let mut line = [0; LINE_SIZE];
+    let mut len = 0;
+    write(class, "pico filter console, type help\r\n> ").await?;
+
+    loop {
+        let mut packet = [0; PACKET_SIZE];
+        let n = class.read_packet(&mut packet).await?;
+        for &byte in &packet[..n] {
+            match byte {
+                b'\r' | b'\n' => {
+                    write(class, "\r\n").await?;
+                    if len > 0 {
+                        let mut out = Output::new();
+                        match core::str::from_utf8(&line[..len]) {
+                            Ok(command) => execute(command, &mut out, stack, storage).await,
+                            Err(_) => out.push("invalid input\r\n"),
+                        }
+                        write(class, out.as_str()).await?;
+                        len = 0;
+                    }
+                    write(class, "> ").await?;
+                }
+                // backspace and delete
+                0x08 | 0x7f => {
+                    if len > 0 {
+                        len -= 1;
+                        write(class, "\x08 \x08").await?;
+                    }
+                }
+                byte if len < LINE_SIZE && (0x20..0x7f).contains(&byte) => {
+                    line[len] = byte;
+                    len += 1;
+                    class.write_packet(&[byte]).await?;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// sends text in packets, a full last packet needs a zero length packet to end the transfer
+async fn write(class: &mut CdcAcmClass<'static, UsbDriver>, text: &str) -> Result<(), EndpointError> {
+    let bytes = text.as_bytes();
+    for chunk in bytes.chunks(PACKET_SIZE) {
+        class.write_packet(chunk).await?;
+    }
+    if !bytes.is_empty() && bytes.len() % PACKET_SIZE == 0 {
+        class.write_packet(&[]).await?;
+    }
+    Ok(())
+}
+
+async fn execute(
+    command: &str,
+    out: &mut Output,
+    stack: &'static Stack<NetDriver<'static>>,
+    storage: Storage,
+) {
+    let mut args = command.split_whitespace();
+    match (args.next(), args.next(), args.next(), args.next()) {
+        (Some("help"), None, None, None) => out.push(HELP),
+        (Some("state"), None, None, None) => {
+            let c = STATE.lock().await;
+            let _ = write!(
+                out,
+                "filter state: {:?}\r\nlast state change: {} ms\r\nwaterlevel: {:?} mm\r\n\
+                 measurement error: {:?}\r\nleak: {:?}\r\nvalve fault: {}\r\n\
+                 pump running: {}\r\npump fault: {:?}\r\nflow fault: {:?}\r\n",
+                c.state.filter_state,
+                c.state.last_state_change,
+                c.state.waterlevel,
+                c.state.measurement_error,
+                c.state.leak,
+                c.state.valve_fault.is_some(),
+                c.state.pump_running,
+                c.state.pump_fault,
+                c.state.flow_fault,
+            );
+        }
+        (Some("config"), None, None, None) => {
+            let config = STATE.lock().await.config;
+            let _ = write!(
+                out,
+                "fill start: {}\r\nfill end: {}\r\nthreshold unit: {:?}\r\n\
+                 clean before fill: {} ms\r\nclean after fill: {} ms\r\n\
+                 leak protection: {}\r\nrecovery: {:?}\r\n",
+                config.waterlevel_fill_start,
+                config.waterlevel_fill_end,
+                config.threshold_unit,
+                config.clean_before_fill_duration,
+                config.clean_after_fill_duration,
+                config.leak_protection,
+                config.recovery,
+            );
+        }
+        (Some("set"), Some(threshold), Some(value), None) => {
+            let Ok(value) = value.parse::<u64>() else {
+                out.push("invalid value\r\n");
+                return;
+            };
+            let mut c = STATE.lock().await;
+            match threshold {
+                "start" => c.config.waterlevel_fill_start = value,
+                "end" => c.config.waterlevel_fill_end = value,
+                _ => {
+                    out.push("unknown threshold\r\n");
+                    return;
+                }
+            }
+            netlog::info!("console set {} threshold to {}", threshold, value);
+            out.push("ok\r\n");
+        }
+        (Some("force"), Some(filter_state), Some(time), None) => {
+            let Ok(time) = time.parse::<u64>() else {
+                out.push("invalid time\r\n");
+                return;
+            };
+            let filter_state = match filter_state {
+                "idle" => FilterState::ForcedIdle(time),
+                "clean" => FilterState::ForcedClean(time),
+                "fill" => FilterState::ForcedFill(time),
+                _ => {
+                    out.push("unknown state\r\n");
+                    return;
+                }
+            };
+            netlog::info!("console forced {:?}", filter_state);
+            STATE.lock().await.state.queued_state = Some(filter_state);
+            out.push("ok\r\n");
+        }
+        (Some("wifi"), Some(ssid), Some(password), None) => {
+            let Some(credentials) = WifiCredentials::new(ssid, password) else {
+                out.push("ssid or password too long\r\n");
+                return;
+            };
+            if let Err(e) = storage.store_wifi_credentials(&credentials) {
+                netlog::warn!("storing wifi credentials failed: {:?}", e);
+                out.push("storing failed, using them until the next reset\r\n");
+            }
+            network::set_wifi_credentials(credentials);
+            network::rejoin();
+            let _ = write!(out, "joining {}\r\n", ssid);
+        }
+        (Some("network"), None, None, None) => {
+            let network_state = STATE.lock().await.network_state;
+            let _ = write!(out, "server: {:?}\r\n", network_state);
+            match stack.config_v4() {
+                Some(config) => {
+                    let _ = write!(out, "address: {}\r\n", config.address);
+                }
+                None => out.push("address: none\r\n"),
+            }
+        }
+        (Some("reboot"), None, None, None) => {
+            netlog::info!("reboot from console");
+            reset::prepare_shutdown().await;
+            reset::reset(state::ResetReason::Console);
+        }
+        _ => out.push("unknown command, type help\r\n"),
+    }
+}
+
+// response of one command, cut off when full
+struct Output {
+    buffer: [u8; 512],
+    len: usize,
+}
+
+impl Output {
+    const fn new() -> Self {
+        Self {
+            buffer: [0; 512],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, text: &str) {
+        let _ = self.write_str(text);
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buffer[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.len + c.len_utf8() > self.buffer.len() {
+                break;
+            }
+            self.len += c.encode_utf8(&mut self.buffer[self.len..]).len();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+
+use cortex_m_rt::{exception, ExceptionFrame};
+use defmt::Format;
+use embassy_rp::pac;
+
+use crate::reset;
+use crate::state::{FilterState, ResetReason};
+use crate::STATE;
+
+pub const MESSAGE_SIZE: usize = 96;
+
+const DUMP_MAGIC: u32 = 0x4352_5348;
+
+#[derive(Format, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CrashKind {
+    Panic = 0,
+    HardFault = 1,
+}
+
+// what the hardware reports about the last reset
+#[derive(Format, PartialEq, Eq, Clone, Copy)]
+pub enum ResetCause {
+    // power on, reset pin or a software reset
+    PowerOn = 0,
+    WatchdogTimeout = 1,
+    WatchdogForced = 2,
+}
+
+#[derive(Format, Clone, Copy)]
+pub struct CrashDump {
+    pub kind: CrashKind,
+    // ms since boot
+    pub uptime: u64,
+    // only set for hard faults
+    pub pc: u32,
+    pub lr: u32,
+    // 0xff if the state was locked
+    pub filter_state: u8,
+    pub message: [u8; MESSAGE_SIZE],
+    pub message_len: u8,
+}
+
+#[repr(C)]
+struct Record {
+    magic: u32,
+    dump: CrashDump,
+}
+
+// kept across the reset like the reset record
+#[link_section = ".uninit.CRASH_DUMP"]
+static mut CRASH_DUMP: MaybeUninit<Record> = MaybeUninit::uninit();
+
+// panic message truncated to the dump size
+struct MessageBuffer {
+    data: [u8; MESSAGE_SIZE],
+    len: usize,
+}
+
+impl Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(MESSAGE_SIZE - self.len);
+        self.data[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+pub fn reset_cause() -> ResetCause {
+    let reason = pac::WATCHDOG.reason().read();
+    if reason.force() {
+        ResetCause::WatchdogForced
+    } else if reason.timer() {
+        ResetCause::WatchdogTimeout
+    } else {
+        ResetCause::PowerOn
+    }
+}
+
+// dump of the crash before the last reset, kept until it was reported
+pub fn read_dump() -> Option<CrashDump> {
+    // SAFETY: read once at boot, the magic guards against uninitialized contents
+    unsafe {
+        let record = addr_of_mut!(CRASH_DUMP).cast::<Record>();
+        if addr_of_mut!((*record).magic).read_volatile() != DUMP_MAGIC {
+            return None;
+        }
+        Some(addr_of_mut!((*record).dump).read_volatile())
+    }
+}
+
+// called once the server has the report so it is sent only once
+pub fn clear_dump() {
+    // SAFETY: a crash writing the record resets right after, nothing else touches it
+    unsafe {
+        let record = addr_of_mut!(CRASH_DUMP).cast::<Record>();
+        addr_of_mut!((*record).magic).write_volatile(0);
+    }
+}
+
+fn store_dump(kind: CrashKind, pc: u32, lr: u32, message: &MessageBuffer) -> ! {
+    // the state is only read if nobody holds it, the crash may have happened inside the lock
+    let filter_state = STATE.try_lock().map_or(0xff, |c| match c.state.filter_state {
+        FilterState::Idle => 0x00,
+        FilterState::CleanBeforeFill => 0x01,
+        FilterState::CleanAfterFill => 0x02,
+        FilterState::Fill => 0x03,
+        FilterState::ForcedFill(_) => 0x04,
+        FilterState::ForcedClean(_) => 0x05,
+        FilterState::ForcedIdle(_) => 0x06,
+    });
+    let dump = CrashDump {
+        kind,
+        uptime: embassy_time::Instant::now().as_millis(),
+        pc,
+        lr,
+        filter_state,
+        message: message.data,
+        message_len: message.len as u8,
+    };
+
+    // SAFETY: interrupts are disabled and the system resets right after
+    unsafe {
+        addr_of_mut!(CRASH_DUMP).cast::<Record>().write_volatile(Record {
+            magic: DUMP_MAGIC,
+            dump,
+        });
+    }
+    // the outputs fall back to low and close the valves during the reset
+    reset::reset(ResetReason::Crash)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    defmt::error!("{}", defmt::Display2Format(info));
+
+    let mut message = MessageBuffer {
+        data: [0; MESSAGE_SIZE],
+        len: 0,
+    };
+    let _ = write!(message, "{info}");
+    store_dump(CrashKind::Panic, 0, 0, &message)
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    let mut message = MessageBuffer {
+        data: [0; MESSAGE_SIZE],
+        len: 0,
+    };
+    let _ = write!(message, "hard fault");
+    store_dump(CrashKind::HardFault, frame.pc(), frame.lr(), &message)
+}
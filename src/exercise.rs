@@ -0,0 +1,92 @@
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::netlog;
+use crate::state::{self, ExerciseResult};
+use crate::supervisor;
+use crate::valve::ValveControler;
+use crate::{STATE, VALVE_COUNT};
+
+// run the exercise if the system idled long enough since the last state change and exercise
+pub async fn exercise_if_due(valve_controler: &mut ValveControler<VALVE_COUNT>) {
+    let c = STATE.lock().await;
+    let config = c.config.exercise;
+    let now = Instant::now().as_millis();
+    let due = config.enabled
+        && c.state.filter_state == state::FilterState::Idle
+        && c.state.queued_state.is_none()
+        && c.state.leak.is_none()
+        && c.state.valve_fault.is_none()
+        && c.state.waterlevel.is_some()
+        && now - c.state.last_state_change > config.idle_threshold
+        && c.state
+            .last_exercise
+            .map_or(true, |t| now - t > config.idle_threshold);
+    let valves = c.config.valves;
+    drop(c);
+
+    if !due {
+        return;
+    }
+
+    netlog::info!("exercising valves");
+    let result = exercise(valve_controler, &config, &valves).await;
+    valve_controler.close_all().await;
+    netlog::info!("valve exercise done: {:?}", result);
+
+    let mut c = STATE.lock().await;
+    c.state.last_exercise = Some(Instant::now().as_millis());
+    c.state.exercise_result = Some(result);
+    if let ExerciseResult::ValveFault(fault) = result {
+        c.state.valve_fault = Some(fault);
+    }
+}
+
+// opens one valve at a time with all others closed, so no flow path opens
+async fn exercise(
+    valve_controler: &mut ValveControler<VALVE_COUNT>,
+    config: &state::ExerciseConfig,
+    valves: &state::ValveConfig,
+) -> ExerciseResult {
+    let start_level = STATE.lock().await.state.waterlevel;
+
+    for valve in 0..valves.count {
+        if let Err(fault) = valve_controler.open_only(valve, valves).await {
+            netlog::warn!("valve {} stuck during exercise", fault.valve);
+            return ExerciseResult::ValveFault(fault);
+        }
+
+        let opened = Instant::now();
+        while opened.elapsed() < Duration::from_millis(config.open_duration) {
+            supervisor::check_in(supervisor::Task::State);
+            if let Some(result) = check_abort(start_level, config).await {
+                return result;
+            }
+            Timer::after(Duration::from_millis(100)).await;
+        }
+
+        if let Err(fault) = valve_controler.close(valves).await {
+            return ExerciseResult::ValveFault(fault);
+        }
+    }
+
+    ExerciseResult::Completed
+}
+
+async fn check_abort(
+    start_level: Option<u64>,
+    config: &state::ExerciseConfig,
+) -> Option<ExerciseResult> {
+    let c = STATE.lock().await;
+    if c.state.leak.is_some() {
+        return Some(ExerciseResult::AbortedLeak);
+    }
+    if c.state.queued_state.is_some() {
+        return Some(ExerciseResult::AbortedCommand);
+    }
+    match (start_level, c.state.waterlevel) {
+        (Some(start), Some(level)) if start.abs_diff(level) > config.max_level_change => {
+            Some(ExerciseResult::AbortedLevel)
+        }
+        _ => None,
+    }
+}
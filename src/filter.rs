@@ -0,0 +1,53 @@
+use crate::state::MeasurementConfig;
+
+pub const MAX_SAMPLES: usize = 9;
+
+pub struct WaterlevelFilter {
+    filtered: Option<u64>,
+    rejected: u8,
+}
+
+impl WaterlevelFilter {
+    pub const fn new() -> Self {
+        Self {
+            filtered: None,
+            rejected: 0,
+        }
+    }
+
+    // returns None if the burst was rejected as an outlier
+    pub fn update(&mut self, samples: &mut [u64], config: &MeasurementConfig) -> Option<u64> {
+        if samples.is_empty() {
+            return None;
+        }
+        let median = median(samples);
+
+        let Some(filtered) = self.filtered else {
+            self.filtered = Some(median);
+            return Some(median);
+        };
+
+        // a real level change keeps jumping, so only reject a limited number of times in a row
+        if median.abs_diff(filtered) > config.max_jump && self.rejected < config.max_rejections {
+            self.rejected += 1;
+            return None;
+        }
+        self.rejected = 0;
+
+        let weight = u64::from(config.smoothing.clamp(1, 100));
+        let filtered = (median * weight + filtered * (100 - weight)) / 100;
+        self.filtered = Some(filtered);
+
+        Some(filtered)
+    }
+}
+
+fn median(samples: &mut [u64]) -> u64 {
+    samples.sort_unstable();
+    let mid = samples.len() / 2;
+    if samples.len() % 2 == 0 {
+        (samples[mid - 1] + samples[mid]) / 2
+    } else {
+        samples[mid]
+    }
+}
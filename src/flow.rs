@@ -0,0 +1,52 @@
+use core::cell::Cell;
+
+use embassy_rp::gpio::{AnyPin, Input};
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+
+use crate::state::{FilterState, FlowConfig, State};
+
+// the M0+ has no atomic add, so the counter lives in a critical section
+static PULSES: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<u32>> =
+    blocking_mutex::Mutex::new(Cell::new(0));
+
+#[embassy_executor::task]
+pub async fn flow_task(mut input: Input<'static, AnyPin>) -> ! {
+    loop {
+        input.wait_for_rising_edge().await;
+        PULSES.lock(|p| p.set(p.get().wrapping_add(1)));
+    }
+}
+
+pub fn to_ml(config: &FlowConfig, pulses: u64) -> u64 {
+    pulses * 1000 / u64::from(config.k_factor.max(1))
+}
+
+// adds the pulses since the last call to the current cycle.
+// returns true if the system is filling without flow
+pub fn account(state: &mut State, config: &FlowConfig) -> bool {
+    let pulses = PULSES.lock(|p| p.replace(0));
+    let flow = &mut state.flow;
+    flow.total_pulses += u64::from(pulses);
+
+    if state.filter_state != flow.cycle_state {
+        match flow.cycle_state {
+            FilterState::Fill | FilterState::ForcedFill(_) => flow.last_fill = flow.cycle_pulses,
+            FilterState::CleanBeforeFill => flow.last_clean_before = flow.cycle_pulses,
+            FilterState::CleanAfterFill => flow.last_clean_after = flow.cycle_pulses,
+            _ => {}
+        }
+        flow.cycle_state = state.filter_state;
+        flow.cycle_pulses = 0;
+    }
+    flow.cycle_pulses += u64::from(pulses);
+
+    let filling = matches!(
+        state.filter_state,
+        FilterState::Fill | FilterState::ForcedFill(_)
+    );
+    let now = embassy_time::Instant::now().as_millis();
+    config.enabled
+        && filling
+        && now - state.last_state_change > config.no_flow_timeout
+        && flow.cycle_pulses < u64::from(config.min_pulses)
+}
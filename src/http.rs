@@ -0,0 +1,559 @@
+use core::fmt::Write;
+
+use cyw43::NetDriver;
+use defmt::debug;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::Stack;
+use embassy_time::{Duration, Instant, Timer};
+use serde::{Deserialize, Serialize};
+
+use crate::netlog;
+use crate::state::{FilterState, NetworkState, NetworkStats, ThresholdUnit, ValveStats};
+use crate::valve::MAX_VALVES;
+use crate::{STATE, TOKEN, VALVE_COUNT};
+
+const HTTP_PORT: u16 = 80;
+const TIMEOUT: Duration = Duration::from_secs(10);
+const REQUEST_SIZE: usize = 1024;
+const BODY_SIZE: usize = 2048;
+
+// asks for the token and polls the api, the page itself holds no data
+const STATUS_PAGE: &str = r#"<!DOCTYPE html>
+<html><head><title>pico filter</title></head><body>
+<h1>pico filter</h1>
+<input id="token" type="password" placeholder="device token">
+<pre id="state"></pre>
+<script>
+async function update() {
+  const token = document.getElementById("token").value;
+  if (token) {
+    const r = await fetch("/api/state", {headers: {Authorization: "Bearer " + token}});
+    document.getElementById("state").textContent = r.ok ? JSON.stringify(await r.json(), null, 2) : r.statusText;
+  }
+  setTimeout(update, 2000);
+}
+update();
+</script>
+</body></html>
+"#;
+
+#[derive(Serialize)]
+struct StateResponse {
+    filter_state: &'static str,
+    forced_time_left: u64,
+    last_state_change: u64,
+    waterlevel: Option<u64>,
+    waterlevel_raw: Option<u64>,
+    measurement_error: Option<u64>,
+    leak: Option<u64>,
+    temperature: Option<i32>,
+    valve_fault: bool,
+    pump_running: bool,
+    pump_fault: Option<u64>,
+    flow_fault: Option<u64>,
+    network_state: &'static str,
+    uptime: u64,
+}
+
+#[derive(Serialize)]
+struct ConfigResponse {
+    waterlevel_fill_start: u64,
+    waterlevel_fill_end: u64,
+    clean_before_fill_duration: u64,
+    clean_after_fill_duration: u64,
+    leak_protection: bool,
+    threshold_unit: &'static str,
+}
+
+// only the given fields are changed
+#[derive(Deserialize)]
+struct ConfigRequest {
+    waterlevel_fill_start: Option<u64>,
+    waterlevel_fill_end: Option<u64>,
+    clean_before_fill_duration: Option<u64>,
+    clean_after_fill_duration: Option<u64>,
+    leak_protection: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct ForceRequest<'a> {
+    state: &'a str,
+    time: u64,
+}
+
+// copied out of the state so the lock is not held while formatting
+struct Metrics {
+    filter_state: FilterState,
+    time_in_state: u64,
+    waterlevel: Option<u64>,
+    measurement_errors: u32,
+    network_stats: NetworkStats,
+    valve_stats: [ValveStats; MAX_VALVES],
+    uptime: u64,
+}
+
+struct Response<'a> {
+    status: &'static str,
+    content_type: &'static str,
+    body: &'a [u8],
+}
+
+impl<'a> Response<'a> {
+    const fn json(body: &'a [u8]) -> Self {
+        Self {
+            status: "200 OK",
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    const fn error(status: &'static str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: status.as_bytes(),
+        }
+    }
+}
+
+// one connection at a time, the api is for technicians on site
+#[embassy_executor::task]
+pub async fn http_task(stack: &'static Stack<NetDriver<'static>>) -> ! {
+    let mut rx_buffer = [0; 1536];
+    let mut tx_buffer = [0; 2048];
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(TIMEOUT));
+        if let Err(e) = socket.accept(HTTP_PORT).await {
+            netlog::warn!("http accept error: {:?}", e);
+            Timer::after(Duration::from_secs(1)).await;
+            continue;
+        }
+
+        if let Err(e) = serve(&mut socket).await {
+            debug!("http connection error: {}", e);
+        }
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
+
+async fn serve(socket: &mut TcpSocket<'_>) -> Result<(), embassy_net::tcp::Error> {
+    let mut request = [0; REQUEST_SIZE];
+    let mut len = 0;
+    // read until the end of the headers and the announced body
+    let (header_len, content_length) = loop {
+        if len == request.len() {
+            return write_response(socket, &Response::error("413 Payload Too Large")).await;
+        }
+        let n = socket.read(&mut request[len..]).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        len += n;
+        if let Some(end) = find(&request[..len], b"\r\n\r\n") {
+            let content_length = header(&request[..end], "content-length")
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(0);
+            break (end + 4, content_length);
+        }
+    };
+    // the length comes from the client before the token is checked, it must not overflow
+    if content_length > REQUEST_SIZE || header_len + content_length > request.len() {
+        return write_response(socket, &Response::error("413 Payload Too Large")).await;
+    }
+    while len < header_len + content_length {
+        let n = socket
+            .read(&mut request[len..header_len + content_length])
+            .await?;
+        if n == 0 {
+            return Ok(());
+        }
+        len += n;
+    }
+
+    let Ok(head) = core::str::from_utf8(&request[..header_len]) else {
+        return write_response(socket, &Response::error("400 Bad Request")).await;
+    };
+    let body = &request[header_len..header_len + content_length];
+    let mut parts = head.split(' ');
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return write_response(socket, &Response::error("400 Bad Request")).await;
+    };
+    debug!("http {} {}", method, path);
+
+    let mut buffer = [0; BODY_SIZE];
+    let response = handle(method, path, head, body, &mut buffer).await;
+    write_response(socket, &response).await
+}
+
+async fn handle<'a>(
+    method: &str,
+    path: &str,
+    head: &str,
+    body: &[u8],
+    buffer: &'a mut [u8],
+) -> Response<'a> {
+    if method == "GET" && path == "/" {
+        return Response {
+            status: "200 OK",
+            content_type: "text/html",
+            body: STATUS_PAGE.as_bytes(),
+        };
+    }
+    if !authorized(head) {
+        return Response::error("401 Unauthorized");
+    }
+
+    match (method, path) {
+        ("GET", "/api/state") => {
+            let c = STATE.lock().await;
+            let now = Instant::now().as_millis();
+            let state = StateResponse {
+                filter_state: filter_state_name(c.state.filter_state),
+                forced_time_left: match c.state.filter_state {
+                    FilterState::ForcedFill(time)
+                    | FilterState::ForcedClean(time)
+                    | FilterState::ForcedIdle(time) => {
+                        time.saturating_sub(now - c.state.last_state_change)
+                    }
+                    _ => 0,
+                },
+                last_state_change: c.state.last_state_change,
+                waterlevel: c.state.waterlevel,
+                waterlevel_raw: c.state.waterlevel_raw,
+                measurement_error: c.state.measurement_error,
+                leak: c.state.leak,
+                temperature: c.state.temperature,
+                valve_fault: c.state.valve_fault.is_some(),
+                pump_running: c.state.pump_running,
+                pump_fault: c.state.pump_fault,
+                flow_fault: c.state.flow_fault,
+                network_state: match c.network_state {
+                    NetworkState::Disconnected => "disconnected",
+                    NetworkState::Registered => "registered",
+                },
+                uptime: now,
+            };
+            drop(c);
+            json(&state, buffer)
+        }
+        ("GET", "/api/config") => {
+            let config = STATE.lock().await.config;
+            let response = ConfigResponse {
+                waterlevel_fill_start: config.waterlevel_fill_start,
+                waterlevel_fill_end: config.waterlevel_fill_end,
+                clean_before_fill_duration: config.clean_before_fill_duration,
+                clean_after_fill_duration: config.clean_after_fill_duration,
+                leak_protection: config.leak_protection,
+                threshold_unit: match config.threshold_unit {
+                    ThresholdUnit::Distance => "distance",
+                    ThresholdUnit::Percent => "percent",
+                },
+            };
+            json(&response, buffer)
+        }
+        ("POST", "/api/config") => {
+            let Ok((request, _)) = serde_json_core::from_slice::<ConfigRequest>(body) else {
+                return Response::error("400 Bad Request");
+            };
+            let mut c = STATE.lock().await;
+            let config = &mut c.config;
+            if let Some(value) = request.waterlevel_fill_start {
+                config.waterlevel_fill_start = value;
+            }
+            if let Some(value) = request.waterlevel_fill_end {
+                config.waterlevel_fill_end = value;
+            }
+            if let Some(value) = request.clean_before_fill_duration {
+                config.clean_before_fill_duration = value;
+            }
+            if let Some(value) = request.clean_after_fill_duration {
+                config.clean_after_fill_duration = value;
+            }
+            if let Some(value) = request.leak_protection {
+                config.leak_protection = value;
+            }
+            netlog::info!("config updated over http");
+            Response::json(b"{}")
+        }
+        ("POST", "/api/force") => {
+            let Ok((request, _)) = serde_json_core::from_slice::<ForceRequest>(body) else {
+                return Response::error("400 Bad Request");
+            };
+            let filter_state = match request.state {
+                "idle" => FilterState::ForcedIdle(request.time),
+                "clean" => FilterState::ForcedClean(request.time),
+                "fill" => FilterState::ForcedFill(request.time),
+                _ => return Response::error("400 Bad Request"),
+            };
+            netlog::info!("forced {:?} over http", filter_state);
+            STATE.lock().await.state.queued_state = Some(filter_state);
+            Response::json(b"{}")
+        }
+        ("GET", "/metrics") => {
+            let c = STATE.lock().await;
+            let now = Instant::now().as_millis();
+            let metrics = Metrics {
+                filter_state: c.state.filter_state,
+                time_in_state: now - c.state.last_state_change,
+                waterlevel: c.state.waterlevel,
+                measurement_errors: c.state.measurement_errors,
+                network_stats: c.network_stats,
+                valve_stats: c.valve_stats,
+                uptime: now,
+            };
+            drop(c);
+
+            let mut body = BodyBuffer {
+                data: buffer,
+                len: 0,
+            };
+            if write_metrics(&mut body, &metrics).is_err() {
+                return Response::error("500 Internal Server Error");
+            }
+            let len = body.len;
+            let data: &'a [u8] = body.data;
+            Response {
+                status: "200 OK",
+                content_type: "text/plain; version=0.0.4",
+                body: &data[..len],
+            }
+        }
+        (_, "/api/state" | "/api/config" | "/api/force" | "/metrics") => {
+            Response::error("405 Method Not Allowed")
+        }
+        _ => Response::error("404 Not Found"),
+    }
+}
+
+fn json<'a, T: Serialize>(value: &T, buffer: &'a mut [u8]) -> Response<'a> {
+    match serde_json_core::to_slice(value, buffer) {
+        Ok(len) => Response::json(&buffer[..len]),
+        Err(_) => Response::error("500 Internal Server Error"),
+    }
+}
+
+// prometheus text exposition format
+fn write_metrics(out: &mut impl Write, m: &Metrics) -> core::fmt::Result {
+    const STATES: [FilterState; 7] = [
+        FilterState::Idle,
+        FilterState::CleanBeforeFill,
+        FilterState::CleanAfterFill,
+        FilterState::Fill,
+        FilterState::ForcedFill(0),
+        FilterState::ForcedClean(0),
+        FilterState::ForcedIdle(0),
+    ];
+    let current = filter_state_name(m.filter_state);
+
+    writeln!(out, "# HELP pico_filter_state Current filter state.")?;
+    writeln!(out, "# TYPE pico_filter_state gauge")?;
+    for state in STATES {
+        let name = filter_state_name(state);
+        writeln!(
+            out,
+            "pico_filter_state{{state=\"{}\"}} {}",
+            name,
+            u8::from(name == current)
+        )?;
+    }
+    writeln!(
+        out,
+        "# HELP pico_filter_state_seconds Time spent in the current filter state."
+    )?;
+    writeln!(out, "# TYPE pico_filter_state_seconds gauge")?;
+    writeln!(
+        out,
+        "pico_filter_state_seconds {}.{:03}",
+        m.time_in_state / 1000,
+        m.time_in_state % 1000
+    )?;
+    // left out until the first valid measurement
+    if let Some(waterlevel) = m.waterlevel {
+        writeln!(
+            out,
+            "# HELP pico_filter_waterlevel_millimeters Filtered distance from the sensor to the water."
+        )?;
+        writeln!(out, "# TYPE pico_filter_waterlevel_millimeters gauge")?;
+        writeln!(out, "pico_filter_waterlevel_millimeters {}", waterlevel)?;
+    }
+    writeln!(
+        out,
+        "# HELP pico_filter_measurement_errors_total Measurements without a valid sample."
+    )?;
+    writeln!(out, "# TYPE pico_filter_measurement_errors_total counter")?;
+    writeln!(
+        out,
+        "pico_filter_measurement_errors_total {}",
+        m.measurement_errors
+    )?;
+    if let Some(rssi) = m.network_stats.rssi {
+        writeln!(
+            out,
+            "# HELP pico_filter_wifi_rssi_dbm Signal strength of the wifi network."
+        )?;
+        writeln!(out, "# TYPE pico_filter_wifi_rssi_dbm gauge")?;
+        writeln!(out, "pico_filter_wifi_rssi_dbm {}", rssi)?;
+    }
+    writeln!(
+        out,
+        "# HELP pico_filter_wifi_joins_total Successful joins of the wifi network."
+    )?;
+    writeln!(out, "# TYPE pico_filter_wifi_joins_total counter")?;
+    writeln!(
+        out,
+        "pico_filter_wifi_joins_total {}",
+        m.network_stats.wifi_joins
+    )?;
+    writeln!(
+        out,
+        "# HELP pico_filter_registrations_total Registrations with the server."
+    )?;
+    writeln!(out, "# TYPE pico_filter_registrations_total counter")?;
+    writeln!(
+        out,
+        "pico_filter_registrations_total {}",
+        m.network_stats.registrations
+    )?;
+    // persisted, so these count across resets
+    writeln!(
+        out,
+        "# HELP pico_filter_valve_actuations_total Times the valve was opened."
+    )?;
+    writeln!(out, "# TYPE pico_filter_valve_actuations_total counter")?;
+    for (i, stats) in m.valve_stats[..VALVE_COUNT].iter().enumerate() {
+        writeln!(
+            out,
+            "pico_filter_valve_actuations_total{{valve=\"{}\"}} {}",
+            i, stats.actuations
+        )?;
+    }
+    writeln!(
+        out,
+        "# HELP pico_filter_valve_open_seconds_total Time the valve was open."
+    )?;
+    writeln!(out, "# TYPE pico_filter_valve_open_seconds_total counter")?;
+    for (i, stats) in m.valve_stats[..VALVE_COUNT].iter().enumerate() {
+        writeln!(
+            out,
+            "pico_filter_valve_open_seconds_total{{valve=\"{}\"}} {}.{:03}",
+            i,
+            stats.open_time / 1000,
+            stats.open_time % 1000
+        )?;
+    }
+    writeln!(out, "# HELP pico_filter_uptime_seconds Time since boot.")?;
+    writeln!(out, "# TYPE pico_filter_uptime_seconds gauge")?;
+    writeln!(
+        out,
+        "pico_filter_uptime_seconds {}.{:03}",
+        m.uptime / 1000,
+        m.uptime % 1000
+    )
+}
+
+async fn write_response(
+    socket: &mut TcpSocket<'_>,
+    response: &Response<'_>,
+) -> Result<(), embassy_net::tcp::Error> {
+    let mut head = HeadBuffer {
+        data: [0; 160],
+        len: 0,
+    };
+    let _ = write!(
+        head,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    write_all(socket, &head.data[..head.len]).await?;
+    write_all(socket, response.body).await
+}
+
+async fn write_all(
+    socket: &mut TcpSocket<'_>,
+    mut data: &[u8],
+) -> Result<(), embassy_net::tcp::Error> {
+    while !data.is_empty() {
+        let n = socket.write(data).await?;
+        data = &data[n..];
+    }
+    Ok(())
+}
+
+// the api takes the device token as bearer token
+fn authorized(head: &str) -> bool {
+    let Some(token) =
+        header(head.as_bytes(), "authorization").and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    // compare in constant time
+    token.len() == TOKEN.len()
+        && token
+            .bytes()
+            .zip(TOKEN.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// value of the first header with the name, names are case insensitive
+fn header<'a>(head: &'a [u8], name: &str) -> Option<&'a str> {
+    let head = core::str::from_utf8(head).ok()?;
+    head.split("\r\n").skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then_some(value.trim())
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+pub const fn filter_state_name(filter_state: FilterState) -> &'static str {
+    match filter_state {
+        FilterState::Idle => "idle",
+        FilterState::CleanBeforeFill => "clean_before_fill",
+        FilterState::CleanAfterFill => "clean_after_fill",
+        FilterState::Fill => "fill",
+        FilterState::ForcedFill(_) => "forced_fill",
+        FilterState::ForcedClean(_) => "forced_clean",
+        FilterState::ForcedIdle(_) => "forced_idle",
+    }
+}
+
+struct HeadBuffer {
+    data: [u8; 160],
+    len: usize,
+}
+
+impl Write for HeadBuffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let len = s.len().min(self.data.len() - self.len);
+        self.data[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+// fails instead of truncating, a cut off exposition would be parsed wrong
+struct BodyBuffer<'a> {
+    data: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for BodyBuffer<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        if end > self.data.len() {
+            return Err(core::fmt::Error);
+        }
+        self.data[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
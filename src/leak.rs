@@ -0,0 +1,90 @@
+use embassy_rp::adc::Channel;
+use embassy_rp::gpio::{AnyPin, Input};
+use embassy_time::{Duration, Timer};
+
+use crate::netlog;
+use crate::temperature::SharedAdc;
+use crate::STATE;
+
+pub const PROBE_COUNT: usize = 2;
+
+// consecutive readings before a probe or the reset button counts
+const DEBOUNCE_COUNT: u8 = 5;
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+pub enum LeakProbe {
+    // digital moisture probe, pulled low when wet
+    Gpio(Input<'static, AnyPin>),
+    // resistive probe in a divider, wet below the threshold
+    Adc(&'static SharedAdc, Channel<'static>, u16),
+}
+
+impl LeakProbe {
+    async fn is_wet(&mut self) -> bool {
+        match self {
+            Self::Gpio(input) => input.is_low(),
+            Self::Adc(adc, channel, threshold) => match adc.lock().await.read(channel).await {
+                Ok(raw) => raw < *threshold,
+                Err(_) => {
+                    netlog::warn!("leak probe adc read failed");
+                    false
+                }
+            },
+        }
+    }
+}
+
+pub struct LeakDetector {
+    probes: [LeakProbe; PROBE_COUNT],
+    reset_button: Input<'static, AnyPin>,
+}
+
+impl LeakDetector {
+    pub const fn new(probes: [LeakProbe; PROBE_COUNT], reset_button: Input<'static, AnyPin>) -> Self {
+        Self {
+            probes,
+            reset_button,
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn leak_task(mut detector: LeakDetector) -> ! {
+    let mut wet_count = [0u8; PROBE_COUNT];
+    let mut button_count = 0u8;
+    loop {
+        for (i, probe) in detector.probes.iter_mut().enumerate() {
+            if probe.is_wet().await {
+                wet_count[i] = wet_count[i].saturating_add(1);
+            } else {
+                wet_count[i] = 0;
+            }
+        }
+
+        if detector.reset_button.is_low() {
+            button_count = button_count.saturating_add(1);
+        } else {
+            button_count = 0;
+        }
+
+        let mut c = STATE.lock().await;
+        if button_count == DEBOUNCE_COUNT && c.state.leak.is_some() {
+            netlog::info!("leak reset by button");
+            c.state.leak = None;
+            c.state.leak_probe = None;
+            wet_count = [0; PROBE_COUNT];
+        }
+
+        // the leak stays latched until reset by button or server
+        if c.state.leak.is_none() {
+            if let Some(i) = wet_count.iter().position(|&n| n >= DEBOUNCE_COUNT) {
+                netlog::warn!("leak detected by probe {}", i);
+                c.state.leak = Some(embassy_time::Instant::now().as_millis());
+                c.state.leak_probe = Some(i as u8);
+            }
+        }
+        drop(c);
+
+        Timer::after(POLL_INTERVAL).await;
+    }
+}
@@ -0,0 +1,165 @@
+use embassy_rp::adc::Channel;
+use embassy_rp::gpio::{AnyPin, Input, Level};
+use embassy_rp::peripherals::PIO1;
+use embassy_rp::pio::{Common, Config as PioConfig, Direction, PioPin, StateMachine};
+use fixed::traits::ToFixed;
+use fixed_macro::types::U56F8;
+
+use crate::netlog;
+use crate::state::{Config, LevelSensorType};
+use crate::temperature::{self, SharedAdc};
+
+// the sensor gives up after 38ms without an echo
+const ECHO_TIMEOUT_US: u32 = 100_000;
+// pushed by the state machine when an edge did not come in time
+const NO_ECHO: u32 = u32::MAX;
+
+// all backends report the distance in mm from the sensor reference point to the water
+pub struct LevelSensor {
+    pub ultrasonic: Ultrasonic,
+    pub pressure: PressureSensor,
+    pub float_switches: FloatSwitches,
+}
+
+impl LevelSensor {
+    pub async fn measure(&mut self, config: &Config, temperature: Option<i32>) -> Option<u64> {
+        match config.level_sensor {
+            LevelSensorType::Ultrasonic => self.ultrasonic.measure(temperature).await,
+            LevelSensorType::Pressure {
+                zero,
+                full_scale,
+                full_scale_height,
+            } => {
+                let raw = self.pressure.read().await?;
+                if full_scale <= zero {
+                    return None;
+                }
+                let height = u64::from(raw.saturating_sub(zero)) * full_scale_height
+                    / u64::from(full_scale - zero);
+                Some(config.tank.sensor_height.saturating_sub(height))
+            }
+            LevelSensorType::FloatSwitches => Some(self.float_switches.measure(config)),
+        }
+    }
+}
+
+// trigger and echo are handled by a pio state machine so the pulse is timed in hardware,
+// a late wakeup of the task does not change the distance
+pub struct Ultrasonic {
+    sm: StateMachine<'static, PIO1, 0>,
+}
+
+impl Ultrasonic {
+    pub fn new(
+        common: &mut Common<'static, PIO1>,
+        mut sm: StateMachine<'static, PIO1, 0>,
+        trig: impl PioPin,
+        echo: impl PioPin,
+    ) -> Self {
+        // one loop iteration takes two cycles, at 2 MHz each count is 1 us
+        let program = pio_proc::pio_asm!(
+            ".wrap_target",
+            "    pull block",
+            "    mov y, osr",
+            // wait out a pulse still running from the last ping
+            "stuck:",
+            "    jmp pin stuck_high",
+            "    jmp trigger",
+            "stuck_high:",
+            "    jmp y-- stuck",
+            "    jmp timeout",
+            // 10 us pulse to send wave
+            "trigger:",
+            "    mov y, osr",
+            "    set pins, 1 [19]",
+            "    set pins, 0",
+            "rise:",
+            "    jmp pin rose",
+            "    jmp y-- rise",
+            "    jmp timeout",
+            "rose:",
+            "    mov x, osr",
+            "high:",
+            "    jmp pin still_high",
+            "    jmp done",
+            "still_high:",
+            "    jmp x-- high",
+            "timeout:",
+            "    mov x, ~null",
+            "done:",
+            "    mov isr, x",
+            "    push",
+            ".wrap",
+        );
+
+        let trig = common.make_pio_pin(trig);
+        let echo = common.make_pio_pin(echo);
+        let mut config = PioConfig::default();
+        config.use_program(&common.load_program(&program.program), &[]);
+        config.set_set_pins(&[&trig]);
+        config.set_jmp_pin(&echo);
+        config.clock_divider = (U56F8!(125_000_000) / U56F8!(2_000_000)).to_fixed();
+        sm.set_config(&config);
+        sm.set_pins(Level::Low, &[&trig]);
+        sm.set_pin_dirs(Direction::Out, &[&trig]);
+        sm.set_pin_dirs(Direction::In, &[&echo]);
+        sm.set_enable(true);
+        Self { sm }
+    }
+
+    async fn measure(&mut self, temperature: Option<i32>) -> Option<u64> {
+        // every wait in the program is bounded by the timeout, so a result always comes back
+        self.sm.tx().wait_push(ECHO_TIMEOUT_US).await;
+        let left = self.sm.rx().wait_pull().await;
+        if left == NO_ECHO {
+            netlog::info!("no echo");
+            return None;
+        }
+        let micros = u64::from(ECHO_TIMEOUT_US - left);
+
+        let distance = (micros * temperature::half_speed_of_sound(temperature)) / 1_000_000;
+
+        Some(distance)
+    }
+}
+
+// pressure transducer at the tank bottom, 0-3.3V or 4-20mA over a shunt
+pub struct PressureSensor {
+    adc: &'static SharedAdc,
+    channel: Channel<'static>,
+}
+
+impl PressureSensor {
+    pub const fn new(adc: &'static SharedAdc, channel: Channel<'static>) -> Self {
+        Self { adc, channel }
+    }
+
+    async fn read(&mut self) -> Option<u16> {
+        self.adc.lock().await.read(&mut self.channel).await.ok()
+    }
+}
+
+// switches close to ground when wet
+pub struct FloatSwitches {
+    low: Input<'static, AnyPin>,
+    high: Input<'static, AnyPin>,
+}
+
+impl FloatSwitches {
+    pub const fn new(low: Input<'static, AnyPin>, high: Input<'static, AnyPin>) -> Self {
+        Self { low, high }
+    }
+
+    // only two points are known, report values that trigger the matching fill thresholds
+    fn measure(&self, config: &Config) -> u64 {
+        let start = config.fill_start_distance();
+        let end = config.fill_end_distance();
+        if self.high.is_low() {
+            end.saturating_sub(1)
+        } else if self.low.is_high() {
+            start + 1
+        } else {
+            (start + end) / 2
+        }
+    }
+}
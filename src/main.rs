@@ -3,32 +3,58 @@
 #![feature(type_alias_impl_trait)]
 #![feature(impl_trait_projections)]
 
+mod console;
+mod crash;
+mod exercise;
+mod filter;
+mod flow;
+mod http;
+mod leak;
+mod level;
 mod messages;
+mod netlog;
 mod network;
+mod ota;
+mod pump;
+mod recovery;
+mod reset;
 mod state;
+mod storage;
+mod supervisor;
+mod tank;
+mod temperature;
 mod valve;
 
+use core::cell::RefCell;
+
 use cyw43_pio::PioSpi;
-use defmt::{info, unwrap, warn};
+use defmt::unwrap;
 use embassy_executor::Spawner;
 use embassy_net::{Config, Stack, StackResources};
 use embassy_rp::{
-    bind_interrupts,
-    gpio::{self, Input, Pin},
-    peripherals::{DMA_CH0, PIN_16, PIN_17, PIN_23, PIN_25, PIO0, PIN_11, PIN_10},
+    adc, bind_interrupts,
+    gpio::{self, AnyPin, Flex, Input, Pin},
+    peripherals::{DMA_CH0, PIN_23, PIN_25, PIO0, PIO1, PIN_11, PIN_10, USB},
     pio::{InterruptHandler, Pio},
+    pwm::Pwm,
+    usb,
+    watchdog::Watchdog,
 };
+use embassy_boot_rp::AlignedBuffer;
 use embassy_sync::{blocking_mutex, mutex::Mutex};
-use embassy_time::{block_for, Duration, Timer};
+use embassy_time::{Duration, Timer};
 use gpio::{Level, Output};
 use static_cell::make_static;
-use {defmt_rtt as _, panic_probe as _};
+use defmt_rtt as _;
 
 type LED = PIN_11;
 type LED2 = PIN_10;
 
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => InterruptHandler<PIO0>;
+    PIO1_IRQ_0 => InterruptHandler<PIO1>;
+    ADC_IRQ_FIFO => adc::InterruptHandler;
+    USBCTRL_IRQ => usb::InterruptHandler<USB>;
 });
 
 const SERVER_IP: embassy_net::IpAddress =
@@ -44,6 +70,22 @@ const ID: &str = "11111111111111111111111111111111";
 const WATERLEVEL_FILL_START: u64 = 500;
 const WATERLEVEL_FILL_END: u64 = 50;
 
+const VALVE_COUNT: usize = 4;
+const _: () = assert!(VALVE_COUNT <= valve::MAX_VALVES);
+// bit i set if valve i is a latching valve driven by an h-bridge
+const LATCHING_VALVES: u8 = 0b0000;
+const LATCHING_PULSE: Duration = Duration::from_millis(50);
+// drive the monostable valves with reduced pwm hold current
+const PWM_VALVES: bool = false;
+// limit switches on the valves report the open position
+const VALVE_FEEDBACK: bool = false;
+
+// flash wears out, so statistics are only written occasionally
+const STATS_PERSIST_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// None disables speed of sound compensation
+const TEMPERATURE_SENSOR: Option<temperature::SensorKind> = Some(temperature::SensorKind::Internal);
+
 static STATE: Mutex<blocking_mutex::raw::CriticalSectionRawMutex, state::Context> =
     Mutex::new(state::Context {
         state: state::State {
@@ -51,8 +93,27 @@ static STATE: Mutex<blocking_mutex::raw::CriticalSectionRawMutex, state::Context
             queued_state: None,
             last_state_change: 0,
             waterlevel: None,
+            waterlevel_raw: None,
             measurement_error: None,
+            measurement_errors: 0,
             leak: None,
+            leak_probe: None,
+            temperature: None,
+            valve_fault: None,
+            last_exercise: None,
+            exercise_result: None,
+            pump_running: false,
+            pump_fault: None,
+            flow: state::FlowState {
+                total_pulses: 0,
+                cycle_state: state::FilterState::Idle,
+                cycle_pulses: 0,
+                last_fill: 0,
+                last_clean_before: 0,
+                last_clean_after: 0,
+            },
+            flow_fault: None,
+            recovery: None,
         },
         config: state::Config {
             waterlevel_fill_start: WATERLEVEL_FILL_START,
@@ -60,9 +121,63 @@ static STATE: Mutex<blocking_mutex::raw::CriticalSectionRawMutex, state::Context
             clean_before_fill_duration: 10 * 1000,
             clean_after_fill_duration: 10 * 1000,
             leak_protection: true,
+            measurement: state::MeasurementConfig {
+                samples: 5,
+                max_jump: 100,
+                max_rejections: 3,
+                smoothing: 30,
+            },
+            level_sensor: state::LevelSensorType::Ultrasonic,
+            tank: state::TankConfig {
+                sensor_height: 1000,
+                full_height: 950,
+                shape: state::TankShape::Cylinder { diameter: 800 },
+            },
+            threshold_unit: state::ThresholdUnit::Distance,
+            valves: state::ValveConfig {
+                count: VALVE_COUNT as u8,
+                idle: 0b0000,
+                clean: 0b0111,
+                fill: 0b1011,
+                dead_time: 200,
+                stagger: 100,
+                pull_in: 300,
+                hold_duty: 40,
+                feedback_delay: 500,
+            },
+            exercise: state::ExerciseConfig {
+                enabled: true,
+                idle_threshold: 7 * 24 * 60 * 60 * 1000,
+                open_duration: 2000,
+                max_level_change: 20,
+            },
+            pump: state::PumpConfig {
+                enabled: false,
+                fill: true,
+                clean: true,
+                start_delay: 1000,
+                run_on: 0,
+                dry_run_timeout: 5 * 60 * 1000,
+                dry_run_min_rise: 10,
+            },
+            flow: state::FlowConfig {
+                enabled: false,
+                k_factor: 450,
+                no_flow_timeout: 60 * 1000,
+                min_pulses: 10,
+            },
+            recovery: state::RecoveryPolicy::Resume,
         },
         network_state: state::NetworkState::Disconnected,
+        network_stats: state::NetworkStats {
+            rssi: None,
+            wifi_joins: 0,
+            registrations: 0,
+        },
         clock_skew: 0,
+        valve_stats: [state::ValveStats::new(); valve::MAX_VALVES],
+        reset_reason: state::ResetReason::Unknown,
+        crash_dump: None,
     });
 
 #[embassy_executor::task]
@@ -84,6 +199,26 @@ async fn net_task(stack: &'static Stack<cyw43::NetDriver<'static>>) -> ! {
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
+    let reset_reason = reset::take_reason();
+    netlog::info!("reset reason: {:?}", reset_reason);
+    let crash_dump = crash::read_dump();
+    if let Some(dump) = &crash_dump {
+        netlog::warn!(
+            "crashed before the reset: {:?} after {} ms: {}",
+            dump.kind,
+            dump.uptime,
+            core::str::from_utf8(&dump.message[..usize::from(dump.message_len)]).unwrap_or("")
+        );
+    }
+    let mut c = STATE.lock().await;
+    c.reset_reason = reset_reason;
+    c.crash_dump = crash_dump;
+    drop(c);
+
+    // the bootloader started the watchdog, it has to be fed during the slow setup below
+    spawner
+        .spawn(supervisor::supervisor_task(Watchdog::new(p.WATCHDOG)))
+        .expect("cant spawn supervisor task");
 
     let fw = include_bytes!("../../../embassy/cyw43-firmware/43439A0.bin");
     let clm = include_bytes!("../../../embassy/cyw43-firmware/43439A0_clm.bin");
@@ -118,28 +253,163 @@ async fn main(spawner: Spawner) {
     let stack = &*make_static!(Stack::new(
         net_device,
         config,
-        make_static!(StackResources::<2>::new()),
+        make_static!(StackResources::<4>::new()),
         seed
     ));
 
+    // init flash storage
+    let flash: &'static storage::SharedFlash = make_static!(blocking_mutex::Mutex::new(
+        RefCell::new(embassy_rp::flash::Flash::<_, _, { storage::FLASH_SIZE }>::new_blocking(p.FLASH))
+    ));
+    let storage = storage::Storage::new(flash);
+    let ota = ota::Ota::new(flash, &mut make_static!(AlignedBuffer([0; 1])).0);
+    unwrap!(spawner.spawn(ota::rollback_task()));
+    if let Some(credentials) = storage.load_wifi_credentials() {
+        netlog::info!("using stored wifi credentials");
+        network::set_wifi_credentials(credentials);
+    }
+
     unwrap!(spawner.spawn(net_task(stack)));
+    unwrap!(spawner.spawn(netlog::syslog_task(stack)));
+    unwrap!(spawner.spawn(http::http_task(stack)));
     spawner
-        .spawn(network::start_network(control, stack))
+        .spawn(network::start_network(control, stack, ota))
         .unwrap();
 
+    // usb serial console
+    let mut usb_config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    usb_config.manufacturer = Some("pico filter");
+    usb_config.product = Some("pico filter console");
+    usb_config.serial_number = Some(ID);
+    usb_config.max_power = 100;
+    usb_config.max_packet_size_0 = 64;
+    let mut usb_builder = embassy_usb::Builder::new(
+        usb::Driver::new(p.USB, Irqs),
+        usb_config,
+        make_static!([0u8; 256]),
+        make_static!([0u8; 256]),
+        make_static!([0u8; 256]),
+        make_static!([0u8; 64]),
+    );
+    let console_class = embassy_usb::class::cdc_acm::CdcAcmClass::new(
+        &mut usb_builder,
+        make_static!(embassy_usb::class::cdc_acm::State::new()),
+        64,
+    );
+    unwrap!(spawner.spawn(console::usb_task(usb_builder.build())));
+    unwrap!(spawner.spawn(console::console_task(console_class, stack, storage)));
+
     // init led pin
     let led1 = Output::new(p.PIN_11, Level::Low);
 
-    // init ultrasonic sensor pins
-    let trig = Output::new(p.PIN_17, Level::Low);
-    let echo = Input::new(p.PIN_16, gpio::Pull::None);
+    // init shared adc
+    let adc: &'static temperature::SharedAdc = make_static!(Mutex::new(adc::Adc::new(
+        p.ADC,
+        Irqs,
+        adc::Config::default()
+    )));
+
+    // init temperature sensor
+    let temperature_sensor = match TEMPERATURE_SENSOR {
+        Some(temperature::SensorKind::Internal) => Some(temperature::TemperatureSensor::Internal(
+            adc,
+            adc::Channel::new_temp_sensor(p.ADC_TEMP_SENSOR),
+        )),
+        Some(temperature::SensorKind::Ds18b20) => Some(temperature::TemperatureSensor::Ds18b20(
+            temperature::OneWire::new(Flex::new(p.PIN_22.degrade())),
+        )),
+        None => None,
+    };
+
+    // init level sensors
+    let mut level_pio = Pio::new(p.PIO1, Irqs);
+    let level_sensor = level::LevelSensor {
+        ultrasonic: level::Ultrasonic::new(
+            &mut level_pio.common,
+            level_pio.sm0,
+            p.PIN_17,
+            p.PIN_16,
+        ),
+        pressure: level::PressureSensor::new(
+            adc,
+            adc::Channel::new_pin(p.PIN_26, gpio::Pull::None),
+        ),
+        float_switches: level::FloatSwitches::new(
+            Input::new(p.PIN_18.degrade(), gpio::Pull::Up),
+            Input::new(p.PIN_19.degrade(), gpio::Pull::Up),
+        ),
+    };
+
+    // init leak probes
+    let leak_detector = leak::LeakDetector::new(
+        [
+            leak::LeakProbe::Gpio(Input::new(p.PIN_20.degrade(), gpio::Pull::Up)),
+            leak::LeakProbe::Adc(
+                adc,
+                adc::Channel::new_pin(p.PIN_27, gpio::Pull::None),
+                2000,
+            ),
+        ],
+        Input::new(p.PIN_21.degrade(), gpio::Pull::Up),
+    );
 
     // init Valve controller
-    let valve1 = valve::Valve::new(Output::new(p.PIN_12, Level::Low));
-    let valve2 = valve::Valve::new(Output::new(p.PIN_13, Level::Low));
-    let valve3 = valve::Valve::new(Output::new(p.PIN_14, Level::Low));
-    let valve4 = valve::Valve::new(Output::new(p.PIN_15, Level::Low));
-    let valve_controler = valve::ValveControler::new(valve1, valve2, valve3, valve4);
+    let valve_feedback = if VALVE_FEEDBACK {
+        [
+            valve::Feedback::LimitSwitch(Input::new(p.PIN_0.degrade(), gpio::Pull::Up)),
+            valve::Feedback::LimitSwitch(Input::new(p.PIN_1.degrade(), gpio::Pull::Up)),
+            valve::Feedback::LimitSwitch(Input::new(p.PIN_2.degrade(), gpio::Pull::Up)),
+            valve::Feedback::LimitSwitch(Input::new(p.PIN_3.degrade(), gpio::Pull::Up)),
+        ]
+    } else {
+        [
+            valve::Feedback::None,
+            valve::Feedback::None,
+            valve::Feedback::None,
+            valve::Feedback::None,
+        ]
+    };
+    let valve_controler = if PWM_VALVES {
+        let slice6: &'static valve::SharedPwmPair =
+            make_static!(blocking_mutex::Mutex::new(RefCell::new(valve::PwmPair::new(
+                valve::PwmSlice::Slice6(Pwm::new_output_ab(
+                    p.PWM_CH6,
+                    p.PIN_12,
+                    p.PIN_13,
+                    valve::PwmPair::config()
+                )),
+                valve::PwmPair::config(),
+            ))));
+        let slice7: &'static valve::SharedPwmPair =
+            make_static!(blocking_mutex::Mutex::new(RefCell::new(valve::PwmPair::new(
+                valve::PwmSlice::Slice7(Pwm::new_output_ab(
+                    p.PWM_CH7,
+                    p.PIN_14,
+                    p.PIN_15,
+                    valve::PwmPair::config()
+                )),
+                valve::PwmPair::config(),
+            ))));
+        valve::ValveControler::<VALVE_COUNT>::new([
+            valve::ValveDriver::Pwm(valve::PwmValve::new(slice6, valve::PwmOutput::A)),
+            valve::ValveDriver::Pwm(valve::PwmValve::new(slice6, valve::PwmOutput::B)),
+            valve::ValveDriver::Pwm(valve::PwmValve::new(slice7, valve::PwmOutput::A)),
+            valve::ValveDriver::Pwm(valve::PwmValve::new(slice7, valve::PwmOutput::B)),
+        ], valve_feedback)
+    } else {
+        valve::ValveControler::<VALVE_COUNT>::new([
+            valve_driver(0, p.PIN_12.degrade(), p.PIN_4.degrade()),
+            valve_driver(1, p.PIN_13.degrade(), p.PIN_5.degrade()),
+            valve_driver(2, p.PIN_14.degrade(), p.PIN_6.degrade()),
+            valve_driver(3, p.PIN_15.degrade(), p.PIN_7.degrade()),
+        ], valve_feedback)
+    };
+
+    // init flow meter
+    let flow_meter = Input::new(p.PIN_9.degrade(), gpio::Pull::Up);
+
+    // init pump
+    let pump = pump::Pump::new(Output::new(p.PIN_8.degrade(), Level::Low));
 
     {
         STATE.lock().await.state.last_state_change = embassy_time::Instant::now().as_millis();
@@ -152,22 +422,47 @@ async fn main(spawner: Spawner) {
         .spawn(show_network_state(Output::new(p.PIN_10, Level::Low)))
         .expect("cant spawn network_show task");
     spawner
-        .spawn(state_update_task(valve_controler))
+        .spawn(state_update_task(valve_controler, pump, storage))
         .expect("cant spawn state update task");
     spawner
-        .spawn(measure_task(trig, echo))
+        .spawn(measure_task(level_sensor))
         .expect("cant spawn measure task");
+    spawner
+        .spawn(flow::flow_task(flow_meter))
+        .expect("cant spawn flow task");
+    spawner
+        .spawn(leak::leak_task(leak_detector))
+        .expect("cant spawn leak task");
+    if let Some(sensor) = temperature_sensor {
+        spawner
+            .spawn(temperature::temperature_task(sensor))
+            .expect("cant spawn temperature task");
+    }
 
     loop {
         Timer::after(Duration::from_secs(5)).await;
     }
 }
 
+// close_pin is only used by latching valves
+fn valve_driver(index: usize, pin: AnyPin, close_pin: AnyPin) -> valve::ValveDriver {
+    if LATCHING_VALVES & (1 << index) != 0 {
+        valve::ValveDriver::Latching(valve::LatchingValve::new(
+            Output::new(pin, Level::Low),
+            Output::new(close_pin, Level::Low),
+            LATCHING_PULSE,
+        ))
+    } else {
+        valve::ValveDriver::Monostable(valve::Valve::new(Output::new(pin, Level::Low)))
+    }
+}
+
 #[embassy_executor::task]
 async fn blink_and_update_task(mut led: Output<'static, LED>) -> ! {
+    let mut last = None;
     loop {
         blink(&mut led);
-        update_serial().await;
+        update_serial(&mut last).await;
         Timer::after(Duration::from_secs(1)).await;
     }
 }
@@ -188,29 +483,152 @@ fn blink<T: Pin>(led: &mut Output<'_, T>) {
     led.toggle();
 }
 
-async fn update_serial() {
+// filter state and fault flags, the state line goes to syslog when they change
+type StateSummary = (state::FilterState, [bool; 5]);
+
+async fn update_serial(last: &mut Option<StateSummary>) {
     let c = STATE.lock().await;
-    info!("State: {}", c.state);
+    let filter_state = c.state.filter_state;
+    let waterlevel = c.state.waterlevel;
+    let pump_running = c.state.pump_running;
+    let faults = [
+        c.state.measurement_error.is_some(),
+        c.state.leak.is_some(),
+        c.state.valve_fault.is_some(),
+        c.state.pump_fault.is_some(),
+        c.state.flow_fault.is_some(),
+    ];
+    drop(c);
+
+    let level = if *last == Some((filter_state, faults)) {
+        netlog::Level::Debug
+    } else {
+        netlog::Level::Info
+    };
+    *last = Some((filter_state, faults));
+    netlog::log(
+        level,
+        format_args!(
+            "State: {:?}, waterlevel {:?}, pump {}, faults: measurement {} leak {} valve {} pump {} flow {}",
+            filter_state,
+            waterlevel,
+            pump_running,
+            u8::from(faults[0]),
+            u8::from(faults[1]),
+            u8::from(faults[2]),
+            u8::from(faults[3]),
+            u8::from(faults[4])
+        ),
+    );
 }
 
 #[embassy_executor::task]
-async fn state_update_task(mut valve_controler: valve::ValveControler) -> ! {
+async fn state_update_task(
+    mut valve_controler: valve::ValveControler<VALVE_COUNT>,
+    mut pump: pump::Pump,
+    storage: storage::Storage,
+) -> ! {
+    if let Some(stats) = storage.load_valve_stats() {
+        valve_controler.restore_stats(&stats);
+    }
+    valve_controler.close_all().await;
+
+    let mut c = STATE.lock().await;
+    let policy = c.config.recovery;
+    let reset_reason = c.reset_reason;
+    recovery::recover(&mut c.state, storage.load_journal(), policy, reset_reason);
+    drop(c);
+    let mut journal = recovery::Journal::new(storage);
+
+    let mut last_persist = embassy_time::Instant::now();
     loop {
-        update_state(&mut valve_controler).await;
+        supervisor::check_in(supervisor::Task::State);
+        if reset::SHUTDOWN_REQUEST.try_take().is_some() {
+            shutdown(&mut valve_controler, &mut pump, &mut journal, storage).await;
+        }
+        update_state(&mut valve_controler, &mut pump).await;
+        exercise::exercise_if_due(&mut valve_controler).await;
+
+        let stats = valve_controler.stats();
+        let mut c = STATE.lock().await;
+        c.valve_stats[..VALVE_COUNT].copy_from_slice(&stats);
+        let flow_config = c.config.flow;
+        if flow::account(&mut c.state, &flow_config) && c.state.flow_fault.is_none() {
+            netlog::warn!("no flow while filling");
+            c.state.flow_fault = Some(embassy_time::Instant::now().as_millis());
+        }
+        let all_stats = c.valve_stats;
+        let filter_state = c.state.filter_state;
+        let last_state_change = c.state.last_state_change;
+        drop(c);
+
+        journal.update(filter_state, last_state_change);
+        if last_persist.elapsed() > STATS_PERSIST_INTERVAL {
+            if let Err(e) = storage.store_valve_stats(&all_stats) {
+                netlog::warn!("storing valve stats failed: {:?}", e);
+            }
+            last_persist = embassy_time::Instant::now();
+        }
+
         Timer::after(Duration::from_millis(500)).await;
     }
 }
 
-async fn update_state(valve_controler: &mut valve::ValveControler) {
+// brings the outputs into a safe state before a reset and keeps them there
+async fn shutdown(
+    valve_controler: &mut valve::ValveControler<VALVE_COUNT>,
+    pump: &mut pump::Pump,
+    journal: &mut recovery::Journal,
+    storage: storage::Storage,
+) -> ! {
+    netlog::info!("shutting down");
+    pump.stop();
+    let mut c = STATE.lock().await;
+    c.state.filter_state = state::FilterState::Idle;
+    c.state.last_state_change = embassy_time::Instant::now().as_millis();
+    c.state.pump_running = false;
+    let last_state_change = c.state.last_state_change;
+    drop(c);
+    // a deliberate reset does not resume the cycle
+    journal.update(state::FilterState::Idle, last_state_change);
+    // the idle pattern may keep valves open, everything is closed across the reset
+    valve_controler.close_all().await;
+
+    let stats = valve_controler.stats();
     let mut c = STATE.lock().await;
+    c.valve_stats[..VALVE_COUNT].copy_from_slice(&stats);
+    let all_stats = c.valve_stats;
+    drop(c);
+    if let Err(e) = storage.store_valve_stats(&all_stats) {
+        netlog::warn!("storing valve stats failed: {:?}", e);
+    }
 
-    // Check for leak if enabled
-    if c.config.leak_protection && c.state.leak.is_some() {
-        valve_controler.idle();
+    reset::SHUTDOWN_DONE.signal(());
+    loop {
+        Timer::after(Duration::from_secs(1)).await;
+    }
+}
+
+async fn update_state(
+    valve_controler: &mut valve::ValveControler<VALVE_COUNT>,
+    pump: &mut pump::Pump,
+) {
+    let mut c = STATE.lock().await;
+
+    // Check for leak if enabled, a stuck valve, dry running pump or missing flow always stops the system
+    if (c.config.leak_protection && c.state.leak.is_some())
+        || c.state.valve_fault.is_some()
+        || c.state.pump_fault.is_some()
+        || c.state.flow_fault.is_some()
+    {
         if c.state.filter_state != state::FilterState::Idle {
             c.state.filter_state = state::FilterState::Idle;
             c.state.last_state_change = embassy_time::Instant::now().as_millis();
         }
+        pump.stop();
+        c.state.pump_running = false;
+        drop(c);
+        valve_controler.close_all().await;
         return;
     }
 
@@ -248,14 +666,14 @@ async fn update_state(valve_controler: &mut valve::ValveControler) {
         }
         state::FilterState::Fill => {
             // check if we are done filling
-            if c.state.waterlevel.unwrap() < c.config.waterlevel_fill_end {
+            if c.state.waterlevel.unwrap() < c.config.fill_end_distance() {
                 c.state.filter_state = state::FilterState::CleanAfterFill;
                 c.state.last_state_change = embassy_time::Instant::now().as_millis();
             }
         }
         state::FilterState::Idle => {
             // check if we need to fill
-            if c.state.waterlevel.unwrap() > c.config.waterlevel_fill_start {
+            if c.state.waterlevel.unwrap() > c.config.fill_start_distance() {
                 c.state.filter_state = state::FilterState::CleanBeforeFill;
                 c.state.last_state_change = embassy_time::Instant::now().as_millis();
             }
@@ -271,8 +689,8 @@ async fn update_state(valve_controler: &mut valve::ValveControler) {
             let current_time = embassy_time::Instant::now().as_millis();
             // check if we are done cleaning
             if c.state.last_state_change + time < current_time {
-                warn!("Forced clean done");
-                warn!("{} + {} < {}", c.state.last_state_change, time, current_time);
+                netlog::warn!("Forced clean done");
+                netlog::warn!("{} + {} < {}", c.state.last_state_change, time, current_time);
                 c.state.filter_state = state::FilterState::Idle;
                 c.state.last_state_change = embassy_time::Instant::now().as_millis();
             }
@@ -286,57 +704,111 @@ async fn update_state(valve_controler: &mut valve::ValveControler) {
         }
     }
 
-    // Update valve state
-    match c.state.filter_state {
-        state::FilterState::CleanBeforeFill => valve_controler.clean(),
-        state::FilterState::CleanAfterFill => valve_controler.clean(),
-        state::FilterState::Fill => valve_controler.fill(),
-        state::FilterState::Idle => valve_controler.idle(),
-        state::FilterState::ForcedFill(_) => valve_controler.fill(),
-        state::FilterState::ForcedClean(_) => valve_controler.clean(),
-        state::FilterState::ForcedIdle(_) => valve_controler.idle(),
+    // Update valve state, switching takes time so release the state first
+    let filter_state = c.state.filter_state;
+    let valves = c.config.valves;
+    let pump_config = c.config.pump;
+    drop(c);
+
+    // valves close during a switch, so the pump has to stop first
+    let pump_demand = pump::Pump::demanded(&pump_config, filter_state);
+    if pump.is_running() && valve_controler.changes(valve_pattern(filter_state, &valves), &valves) {
+        // keep running on the old path for a while, the valves switch on a later tick
+        if !pump_demand && !pump.run_on_over(pump_config.run_on) {
+            return;
+        }
+        pump.stop();
     }
-}
 
-#[embassy_executor::task]
-async fn measure_task(mut trig: Output<'static, PIN_17>, echo: Input<'static, PIN_16>) -> ! {
-    loop {
-        if let Some(d) = measure(&mut trig, &echo) {
-            let mut c = STATE.lock().await;
-            c.state.waterlevel = Some(d);
-        } else {
-            let mut c = STATE.lock().await;
-            c.state.measurement_error = Some(embassy_time::Instant::now().as_millis());
-        }
+    let result = match filter_state {
+        state::FilterState::CleanBeforeFill => valve_controler.clean(&valves).await,
+        state::FilterState::CleanAfterFill => valve_controler.clean(&valves).await,
+        state::FilterState::Fill => valve_controler.fill(&valves).await,
+        state::FilterState::Idle => valve_controler.idle(&valves).await,
+        state::FilterState::ForcedFill(_) => valve_controler.fill(&valves).await,
+        state::FilterState::ForcedClean(_) => valve_controler.clean(&valves).await,
+        state::FilterState::ForcedIdle(_) => valve_controler.idle(&valves).await,
+    };
+
+    if let Err(fault) = result {
+        netlog::warn!("valve {} stuck, expected open: {}", fault.valve, fault.expected_open);
+        pump.stop();
+        valve_controler.close_all().await;
+        let mut c = STATE.lock().await;
+        c.state.valve_fault = Some(fault);
+        c.state.pump_running = false;
+        c.state.filter_state = state::FilterState::Idle;
+        c.state.last_state_change = embassy_time::Instant::now().as_millis();
+        return;
+    }
 
-        Timer::after(Duration::from_secs(5)).await;
+    let mut c = STATE.lock().await;
+    let filling = matches!(
+        filter_state,
+        state::FilterState::Fill | state::FilterState::ForcedFill(_)
+    );
+    if pump.update(
+        &pump_config,
+        pump_demand,
+        valve_controler.is_open(),
+        filling,
+        c.state.waterlevel,
+    ) {
+        netlog::warn!("pump stopped, waterlevel did not rise");
+        c.state.pump_fault = Some(embassy_time::Instant::now().as_millis());
     }
+    c.state.pump_running = pump.is_running();
 }
 
-fn measure<T: Pin, U: Pin>(trig: &mut Output<'static, T>, echo: &Input<'static, U>) -> Option<u64> {
-    // 10 us pulse to send wave
-    trig.set_high();
-    block_for(Duration::from_micros(10));
-    trig.set_low();
-
-    let time = embassy_time::Instant::now();
-    while echo.is_low() {
-        if time.elapsed() > Duration::from_secs(2) {
-            info!("timeout waiting for high");
-            return None;
-        }
+fn valve_pattern(filter_state: state::FilterState, valves: &state::ValveConfig) -> u8 {
+    match filter_state {
+        state::FilterState::CleanBeforeFill => valves.clean,
+        state::FilterState::CleanAfterFill => valves.clean,
+        state::FilterState::Fill => valves.fill,
+        state::FilterState::Idle => valves.idle,
+        state::FilterState::ForcedFill(_) => valves.fill,
+        state::FilterState::ForcedClean(_) => valves.clean,
+        state::FilterState::ForcedIdle(_) => valves.idle,
     }
+}
 
-    let time = embassy_time::Instant::now();
-    while echo.is_high() {
-        if time.elapsed() > Duration::from_secs(2) {
-            info!("timeout waiting for low");
-            return None;
+#[embassy_executor::task]
+async fn measure_task(mut sensor: level::LevelSensor) -> ! {
+    let mut filter = filter::WaterlevelFilter::new();
+    loop {
+        supervisor::check_in(supervisor::Task::Measure);
+        let c = STATE.lock().await;
+        let config = c.config;
+        let temperature = c.state.temperature;
+        drop(c);
+
+        let mut samples = [0; filter::MAX_SAMPLES];
+        let mut count = 0;
+        for _ in 0..usize::from(config.measurement.samples).clamp(1, filter::MAX_SAMPLES) {
+            if let Some(d) = sensor.measure(&config, temperature).await {
+                samples[count] = d;
+                count += 1;
+            }
+            // let echoes of the last ping die down
+            Timer::after(Duration::from_millis(60)).await;
         }
-    }
-    let past = time.elapsed();
 
-    let distance = (past.as_ticks() * 171_605) / embassy_time::TICK_HZ;
+        let mut c = STATE.lock().await;
+        if count == 0 {
+            c.state.measurement_error = Some(embassy_time::Instant::now().as_millis());
+            c.state.measurement_errors = c.state.measurement_errors.wrapping_add(1);
+        } else {
+            c.state.waterlevel_raw = Some(samples[count - 1]);
+            if config.level_sensor.is_discrete() {
+                c.state.waterlevel = Some(samples[count - 1]);
+            } else if let Some(d) = filter.update(&mut samples[..count], &config.measurement) {
+                c.state.waterlevel = Some(d);
+            } else {
+                netlog::info!("rejected waterlevel reading {}", samples[count / 2]);
+            }
+        }
+        drop(c);
 
-    Some(distance)
+        Timer::after(Duration::from_secs(5)).await;
+    }
 }
@@ -1,6 +1,11 @@
 use defmt::{info, Format};
 
+use crate::crash;
+use crate::flow;
+use crate::ota;
 use crate::state;
+use crate::tank;
+use crate::valve;
 
 #[derive(Format)]
 pub struct Message {
@@ -23,9 +28,14 @@ pub enum MessagePayload {
     Accepted(Accepted),
     Heartbeat(Heartbeat),
     HeartbeatResponse(HeartbeatResponse),
+    Statistics(Statistics),
+    FirmwareRequest(FirmwareRequest),
+    FirmwareChunk(FirmwareChunk),
+    ResetAck(ResetAck),
+    CrashReport(CrashReport),
 }
 
-// size: 68 bytes
+// size: 70 bytes
 #[derive(Format)]
 pub struct Register {
     pub dev_id: [u8; 32],
@@ -33,6 +43,8 @@ pub struct Register {
     pub dev_type: u8,
     pub firmware_version: u16,
     pub needs_config: u8,
+    pub reset_reason: u8,
+    pub starved_task: u8,
 }
 
 // size: 9 bytes
@@ -43,7 +55,7 @@ pub struct Accepted {
     pub config: Option<Config>,
 }
 
-// size 33 bytes
+// size 35 bytes
 #[derive(Format)]
 pub struct Config {
     pub waterlevel_fill_start: u64,
@@ -51,9 +63,11 @@ pub struct Config {
     pub clean_before_fill_duration: u64,
     pub clean_after_fill_duration: u64,
     pub leak_protection: u8,
+    pub threshold_unit: u8,
+    pub recovery_policy: u8,
 }
 
-// size: 87 bytes
+// size: 161 bytes
 #[derive(Format)]
 pub struct Heartbeat {
     pub dev_id: [u8; 32],
@@ -67,6 +81,68 @@ pub struct Heartbeat {
     pub measurement_error_count: u32,
     pub leak: u8,
     pub leak_occured: u64,
+    pub waterlevel_raw: u64,
+    pub temperature_available: u8,
+    pub temperature: i16,
+    pub fill_height: u64,
+    pub volume: u32,
+    pub fill_percent: u8,
+    pub leak_probe: u8,
+    pub valve_fault: u8,
+    pub valve_fault_valve: u8,
+    pub valve_fault_expected: u8,
+    pub last_exercise: u64,
+    pub exercise_result: u8,
+    pub pump_running: u8,
+    pub pump_fault: u8,
+    pub volume_total: u64,
+    pub volume_cycle: u32,
+    pub volume_last_fill: u32,
+    pub volume_last_clean_before: u32,
+    pub volume_last_clean_after: u32,
+    pub flow_fault: u8,
+    pub recovered_state: u8,
+    pub recovery_action: u8,
+    pub recovered_elapsed: u64,
+}
+
+// size: 33 + 12 * valve_count bytes
+#[derive(Format)]
+pub struct Statistics {
+    pub dev_id: [u8; 32],
+    pub valve_count: u8,
+    pub valves: [state::ValveStats; valve::MAX_VALVES],
+}
+
+// size: 40 bytes
+#[derive(Format)]
+pub struct FirmwareRequest {
+    pub dev_id: [u8; 32],
+    pub version: u16,
+    pub offset: u32,
+    pub length: u16,
+}
+
+// size: 10 + length bytes
+#[derive(Format)]
+pub struct FirmwareChunk {
+    pub offset: u32,
+    pub length: u16,
+    pub crc: u32,
+    pub data: [u8; ota::CHUNK_SIZE],
+}
+
+// size: 32 bytes
+#[derive(Format)]
+pub struct ResetAck {
+    pub dev_id: [u8; 32],
+}
+
+// size: 147 bytes
+#[derive(Format)]
+pub struct CrashReport {
+    pub dev_id: [u8; 32],
+    pub dump: crash::CrashDump,
 }
 
 // size: 1 byte
@@ -86,6 +162,11 @@ pub enum CommandType {
     ResetMeasurementError,
     NewFirmware(NewFirmware),
     ResetDevice,
+    UpdateValveMapping(ValveMapping),
+    ResetValveFault,
+    ResetPumpFault,
+    ResetFlowFault,
+    SetLogLevel(u8),
 }
 
 // size: 9 bytes
@@ -107,11 +188,24 @@ pub struct SetResetLeak {
     pub leak: u8,
 }
 
-// size 10 bytes
+// size: 20 bytes
+#[derive(Format)]
+pub struct ValveMapping {
+    pub count: u8,
+    pub idle: u8,
+    pub clean: u8,
+    pub fill: u8,
+    pub dead_time: u64,
+    pub stagger: u64,
+}
+
+// size 106 bytes
 #[derive(Format)]
 pub struct NewFirmware {
-    version: u16,
-    size: u64,
+    pub version: u16,
+    pub size: u64,
+    pub sha256: [u8; 32],
+    pub signature: [u8; 64],
 }
 
 // size: 1 byte
@@ -122,6 +216,18 @@ struct MessageEnd {
 
 pub fn create_heartbeat(state: &state::Context) -> Heartbeat {
     let current_time = embassy_time::Instant::now().as_millis();
+    let fill_height = state
+        .state
+        .waterlevel
+        .map(|d| tank::fill_height(&state.config.tank, d))
+        .unwrap_or(0);
+    let flow_config = &state.config.flow;
+    let flow_state = &state.state.flow;
+    let volume = |pulses| {
+        flow::to_ml(flow_config, pulses)
+            .try_into()
+            .unwrap_or(u32::MAX)
+    };
     let mut id = [0x01; 32];
     id.copy_from_slice(crate::ID.as_bytes());
     Heartbeat {
@@ -146,14 +252,80 @@ pub fn create_heartbeat(state: &state::Context) -> Heartbeat {
         waterlevel: state.state.waterlevel.unwrap_or(0),
         measurement_error: u8::from(state.state.measurement_error.unwrap_or(0) != 0),
         measurement_error_occured: 0, // TODO: implement
-        measurement_error_count: state
+        measurement_error_count: state.state.measurement_errors,
+        leak: u8::from(state.state.leak.is_some()),
+        leak_occured: state.state.leak.map(|t| t  + state.clock_skew).unwrap_or(0),
+        waterlevel_raw: state.state.waterlevel_raw.unwrap_or(0),
+        temperature_available: u8::from(state.state.temperature.is_some()),
+        temperature: state
             .state
-            .measurement_error
+            .temperature
             .unwrap_or(0)
             .try_into()
             .unwrap_or(0),
-        leak: u8::from(state.state.leak.is_some()),
-        leak_occured: state.state.leak.map(|t| t  + state.clock_skew).unwrap_or(0),
+        fill_height,
+        volume: (tank::volume_ml(&state.config.tank, fill_height) / 1000)
+            .try_into()
+            .unwrap_or(u32::MAX),
+        fill_percent: tank::percent(&state.config.tank, fill_height)
+            .try_into()
+            .unwrap_or(u8::MAX),
+        leak_probe: state.state.leak_probe.unwrap_or(0xff),
+        valve_fault: u8::from(state.state.valve_fault.is_some()),
+        valve_fault_valve: state.state.valve_fault.map(|f| f.valve).unwrap_or(0),
+        valve_fault_expected: state
+            .state
+            .valve_fault
+            .map(|f| u8::from(f.expected_open))
+            .unwrap_or(0),
+        last_exercise: state
+            .state
+            .last_exercise
+            .map(|t| t + state.clock_skew)
+            .unwrap_or(0),
+        exercise_result: match state.state.exercise_result {
+            None => 0x00,
+            Some(state::ExerciseResult::Completed) => 0x01,
+            Some(state::ExerciseResult::AbortedLeak) => 0x02,
+            Some(state::ExerciseResult::AbortedLevel) => 0x03,
+            Some(state::ExerciseResult::AbortedCommand) => 0x04,
+            Some(state::ExerciseResult::ValveFault(_)) => 0x05,
+        },
+        pump_running: u8::from(state.state.pump_running),
+        pump_fault: u8::from(state.state.pump_fault.is_some()),
+        volume_total: flow::to_ml(flow_config, flow_state.total_pulses),
+        volume_cycle: volume(flow_state.cycle_pulses),
+        volume_last_fill: volume(flow_state.last_fill),
+        volume_last_clean_before: volume(flow_state.last_clean_before),
+        volume_last_clean_after: volume(flow_state.last_clean_after),
+        flow_fault: u8::from(state.state.flow_fault.is_some()),
+        recovered_state: match state.state.recovery.map(|r| r.filter_state) {
+            None => 0xff,
+            Some(state::FilterState::Idle) => 0x00,
+            Some(state::FilterState::CleanBeforeFill) => 0x01,
+            Some(state::FilterState::CleanAfterFill) => 0x02,
+            Some(state::FilterState::Fill) => 0x03,
+            Some(state::FilterState::ForcedFill(_)) => 0x04,
+            Some(state::FilterState::ForcedClean(_)) => 0x05,
+            Some(state::FilterState::ForcedIdle(_)) => 0x06,
+        },
+        recovery_action: match state.state.recovery.map(|r| r.action) {
+            None => 0x00,
+            Some(state::RecoveryAction::Resumed) => 0x01,
+            Some(state::RecoveryAction::AbortedPolicy) => 0x02,
+            Some(state::RecoveryAction::AbortedCrash) => 0x03,
+        },
+        recovered_elapsed: state.state.recovery.map_or(0, |r| r.elapsed),
+    }
+}
+
+pub fn create_statistics(state: &state::Context) -> Statistics {
+    let mut id = [0x01; 32];
+    id.copy_from_slice(crate::ID.as_bytes());
+    Statistics {
+        dev_id: id,
+        valve_count: state.config.valves.count,
+        valves: state.valve_stats,
     }
 }
 
@@ -161,6 +333,10 @@ pub fn encode_message(message: MessagePayload) -> Result<([u8; 4096], usize), &'
     let bytes = match message {
         MessagePayload::Register(register) => encode_register_message(&register),
         MessagePayload::Heartbeat(heartbeat) => encode_heartbeat_message(&heartbeat),
+        MessagePayload::Statistics(statistics) => encode_statistics_message(&statistics),
+        MessagePayload::FirmwareRequest(request) => encode_firmware_request_message(&request),
+        MessagePayload::ResetAck(ack) => encode_reset_ack_message(&ack),
+        MessagePayload::CrashReport(report) => encode_crash_report_message(&report),
         _ => return Err("wrong message type"),
     };
 
@@ -177,9 +353,9 @@ fn encode_header(header: &MessageHeader) -> [u8; 9] {
     buffer
 }
 
-// buffer size: hearbeat: 87
-fn encode_heartbeat(heartbeat: &Heartbeat) -> [u8; 87] {
-    let mut buffer = [0; 87];
+// buffer size: hearbeat: 161
+fn encode_heartbeat(heartbeat: &Heartbeat) -> [u8; 161] {
+    let mut buffer = [0; 161];
     buffer[0..32].copy_from_slice(&heartbeat.dev_id);
     buffer[32..40].copy_from_slice(&heartbeat.dev_time.to_be_bytes());
     buffer[40] = heartbeat.filter_state;
@@ -191,6 +367,29 @@ fn encode_heartbeat(heartbeat: &Heartbeat) -> [u8; 87] {
     buffer[74..78].copy_from_slice(&heartbeat.measurement_error_count.to_be_bytes());
     buffer[78] = heartbeat.leak;
     buffer[79..87].copy_from_slice(&heartbeat.leak_occured.to_be_bytes());
+    buffer[87..95].copy_from_slice(&heartbeat.waterlevel_raw.to_be_bytes());
+    buffer[95] = heartbeat.temperature_available;
+    buffer[96..98].copy_from_slice(&heartbeat.temperature.to_be_bytes());
+    buffer[98..106].copy_from_slice(&heartbeat.fill_height.to_be_bytes());
+    buffer[106..110].copy_from_slice(&heartbeat.volume.to_be_bytes());
+    buffer[110] = heartbeat.fill_percent;
+    buffer[111] = heartbeat.leak_probe;
+    buffer[112] = heartbeat.valve_fault;
+    buffer[113] = heartbeat.valve_fault_valve;
+    buffer[114] = heartbeat.valve_fault_expected;
+    buffer[115..123].copy_from_slice(&heartbeat.last_exercise.to_be_bytes());
+    buffer[123] = heartbeat.exercise_result;
+    buffer[124] = heartbeat.pump_running;
+    buffer[125] = heartbeat.pump_fault;
+    buffer[126..134].copy_from_slice(&heartbeat.volume_total.to_be_bytes());
+    buffer[134..138].copy_from_slice(&heartbeat.volume_cycle.to_be_bytes());
+    buffer[138..142].copy_from_slice(&heartbeat.volume_last_fill.to_be_bytes());
+    buffer[142..146].copy_from_slice(&heartbeat.volume_last_clean_before.to_be_bytes());
+    buffer[146..150].copy_from_slice(&heartbeat.volume_last_clean_after.to_be_bytes());
+    buffer[150] = heartbeat.flow_fault;
+    buffer[151] = heartbeat.recovered_state;
+    buffer[152] = heartbeat.recovery_action;
+    buffer[153..161].copy_from_slice(&heartbeat.recovered_elapsed.to_be_bytes());
 
     buffer
 }
@@ -200,23 +399,101 @@ fn encode_heartbeat_message(heartbeat: &Heartbeat) -> ([u8; 4096], usize) {
     buffer[0..9].copy_from_slice(&encode_header(&MessageHeader {
         magic: 0xfafafaff,
         typ: 0x03,
-        length: 97,
+        length: 171,
     }));
 
-    buffer[9..96].copy_from_slice(&encode_heartbeat(heartbeat));
-    buffer[96] = 0;
+    buffer[9..170].copy_from_slice(&encode_heartbeat(heartbeat));
+    buffer[170] = 0;
 
-    (buffer, 97)
+    (buffer, 171)
 }
 
-// buffer size: register: 68
-fn encode_register(register: &Register) -> [u8; 68] {
-    let mut buffer = [0; 68];
+fn encode_statistics_message(statistics: &Statistics) -> ([u8; 4096], usize) {
+    let count = usize::from(statistics.valve_count).min(valve::MAX_VALVES);
+    let length = 9 + 33 + 12 * count + 1;
+
+    let mut buffer = [0; 4096];
+    buffer[0..9].copy_from_slice(&encode_header(&MessageHeader {
+        magic: 0xfafafaff,
+        typ: 0x05,
+        length: length as u32,
+    }));
+
+    buffer[9..41].copy_from_slice(&statistics.dev_id);
+    buffer[41] = count as u8;
+    for (i, stats) in statistics.valves[..count].iter().enumerate() {
+        let offset = 42 + 12 * i;
+        buffer[offset..offset + 4].copy_from_slice(&stats.actuations.to_be_bytes());
+        buffer[offset + 4..offset + 12].copy_from_slice(&stats.open_time.to_be_bytes());
+    }
+    buffer[length - 1] = 0;
+
+    (buffer, length)
+}
+
+fn encode_firmware_request_message(request: &FirmwareRequest) -> ([u8; 4096], usize) {
+    let mut buffer = [0; 4096];
+    buffer[0..9].copy_from_slice(&encode_header(&MessageHeader {
+        magic: 0xfafafaff,
+        typ: 0x06,
+        length: 50,
+    }));
+
+    buffer[9..41].copy_from_slice(&request.dev_id);
+    buffer[41..43].copy_from_slice(&request.version.to_be_bytes());
+    buffer[43..47].copy_from_slice(&request.offset.to_be_bytes());
+    buffer[47..49].copy_from_slice(&request.length.to_be_bytes());
+    buffer[49] = 0;
+
+    (buffer, 50)
+}
+
+fn encode_reset_ack_message(ack: &ResetAck) -> ([u8; 4096], usize) {
+    let mut buffer = [0; 4096];
+    buffer[0..9].copy_from_slice(&encode_header(&MessageHeader {
+        magic: 0xfafafaff,
+        typ: 0x08,
+        length: 42,
+    }));
+
+    buffer[9..41].copy_from_slice(&ack.dev_id);
+    buffer[41] = 0;
+
+    (buffer, 42)
+}
+
+fn encode_crash_report_message(report: &CrashReport) -> ([u8; 4096], usize) {
+    let mut buffer = [0; 4096];
+    buffer[0..9].copy_from_slice(&encode_header(&MessageHeader {
+        magic: 0xfafafaff,
+        typ: 0x09,
+        length: 157,
+    }));
+
+    let dump = &report.dump;
+    buffer[9..41].copy_from_slice(&report.dev_id);
+    buffer[41] = dump.kind as u8;
+    buffer[42..50].copy_from_slice(&dump.uptime.to_be_bytes());
+    buffer[50..54].copy_from_slice(&dump.pc.to_be_bytes());
+    buffer[54..58].copy_from_slice(&dump.lr.to_be_bytes());
+    buffer[58] = dump.filter_state;
+    buffer[59] = dump.message_len.min(crash::MESSAGE_SIZE as u8);
+    buffer[60..156].copy_from_slice(&dump.message);
+    buffer[156] = 0;
+
+    (buffer, 157)
+}
+
+// buffer size: register: 70
+fn encode_register(register: &Register) -> [u8; 70] {
+    let mut buffer = [0; 70];
     buffer[0..32].copy_from_slice(&register.dev_id);
     buffer[32..64].copy_from_slice(&register.token);
     buffer[64] = register.dev_type;
     buffer[65..67].copy_from_slice(&register.firmware_version.to_be_bytes());
     buffer[67] = register.needs_config;
+    buffer[68] = register.reset_reason;
+    buffer[69] = register.starved_task;
 
     buffer
 }
@@ -226,13 +503,13 @@ pub fn encode_register_message(register: &Register) -> ([u8; 4096], usize) {
     buffer[0..9].copy_from_slice(&encode_header(&MessageHeader {
         magic: 0xfafafaff,
         typ: 0x01,
-        length: 78,
+        length: 80,
     }));
 
-    buffer[9..77].copy_from_slice(&encode_register(register));
-    buffer[77] = 0;
+    buffer[9..79].copy_from_slice(&encode_register(register));
+    buffer[79] = 0;
 
-    (buffer, 78)
+    (buffer, 80)
 }
 
 pub fn decode_message(buffer: &[u8]) -> Result<Message, &'static str> {
@@ -258,7 +535,7 @@ fn decode_header(buffer: &[u8]) -> Result<MessageHeader, &'static str> {
         return Err("magic does not match");
     }
     let typ = buffer[4];
-    if !(1..=4).contains(&typ) {
+    if !matches!(typ, 1..=4 | 7) {
         return Err("typ does not match");
     }
     let length = u32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]);
@@ -270,6 +547,7 @@ fn decode_payload(buffer: &[u8], typ: u8) -> Result<MessagePayload, &'static str
     Ok(match typ {
         2 => MessagePayload::Accepted(decode_accepted(buffer)),
         4 => MessagePayload::HeartbeatResponse(decode_heartbeat_response(buffer)),
+        7 => MessagePayload::FirmwareChunk(decode_firmware_chunk(buffer)),
         _ => return Err("typ does not match2"),
     })
 }
@@ -314,6 +592,8 @@ const fn decode_config(buffer: &[u8]) -> Config {
         buffer[31],
     ]);
     let leak_protection = buffer[32];
+    let threshold_unit = buffer[33];
+    let recovery_policy = buffer[34];
 
     Config {
         waterlevel_fill_start,
@@ -321,6 +601,8 @@ const fn decode_config(buffer: &[u8]) -> Config {
         clean_before_fill_duration,
         clean_after_fill_duration,
         leak_protection,
+        threshold_unit,
+        recovery_policy,
     }
 }
 
@@ -335,6 +617,11 @@ fn decode_heartbeat_response(buffer: &[u8]) -> HeartbeatResponse {
         5 => CommandType::ResetMeasurementError,
         6 => CommandType::NewFirmware(decode_new_firmware(&buffer[1..buffer.len() - 1])),
         7 => CommandType::ResetDevice,
+        8 => CommandType::UpdateValveMapping(decode_valve_mapping(&buffer[1..buffer.len() - 1])),
+        9 => CommandType::ResetValveFault,
+        10 => CommandType::ResetPumpFault,
+        11 => CommandType::ResetFlowFault,
+        12 => CommandType::SetLogLevel(buffer[1]),
         _ => {
             return HeartbeatResponse {
                 command_type,
@@ -372,11 +659,56 @@ const fn decode_set_reset_leak(buffer: &[u8]) -> SetResetLeak {
     SetResetLeak { leak }
 }
 
-const fn decode_new_firmware(buffer: &[u8]) -> NewFirmware {
+const fn decode_valve_mapping(buffer: &[u8]) -> ValveMapping {
+    let dead_time = u64::from_be_bytes([
+        buffer[4], buffer[5], buffer[6], buffer[7], buffer[8], buffer[9], buffer[10], buffer[11],
+    ]);
+    let stagger = u64::from_be_bytes([
+        buffer[12], buffer[13], buffer[14], buffer[15], buffer[16], buffer[17], buffer[18],
+        buffer[19],
+    ]);
+
+    ValveMapping {
+        count: buffer[0],
+        idle: buffer[1],
+        clean: buffer[2],
+        fill: buffer[3],
+        dead_time,
+        stagger,
+    }
+}
+
+fn decode_new_firmware(buffer: &[u8]) -> NewFirmware {
     let version = u16::from_be_bytes([buffer[0], buffer[1]]);
     let size = u64::from_be_bytes([
         buffer[2], buffer[3], buffer[4], buffer[5], buffer[6], buffer[7], buffer[8], buffer[9],
     ]);
 
-    NewFirmware { version, size }
+    let mut sha256 = [0; 32];
+    sha256.copy_from_slice(&buffer[10..42]);
+    let mut signature = [0; 64];
+    signature.copy_from_slice(&buffer[42..106]);
+
+    NewFirmware {
+        version,
+        size,
+        sha256,
+        signature,
+    }
+}
+
+fn decode_firmware_chunk(buffer: &[u8]) -> FirmwareChunk {
+    let offset = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+    let length = u16::from_be_bytes([buffer[4], buffer[5]]).min(ota::CHUNK_SIZE as u16);
+    let crc = u32::from_be_bytes([buffer[6], buffer[7], buffer[8], buffer[9]]);
+    let mut data = [0; ota::CHUNK_SIZE];
+    let len = usize::from(length);
+    data[..len].copy_from_slice(&buffer[10..10 + len]);
+
+    FirmwareChunk {
+        offset,
+        length,
+        crc,
+        data,
+    }
 }
@@ -0,0 +1,195 @@
+use core::cell::{Cell, RefCell};
+use core::fmt::{self, Write};
+
+use cyw43::NetDriver;
+use defmt::Format;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Stack};
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+
+use crate::{ID, SERVER_IP};
+
+// lines kept while the network is down, the oldest are dropped first
+const BUFFER_LINES: usize = 16;
+const LINE_SIZE: usize = 120;
+const SYSLOG_PORT: u16 = 514;
+// local0
+const SYSLOG_FACILITY: u8 = 16;
+
+#[derive(Format, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Level {
+    Debug = 0,
+    Info = 1,
+    Warn = 2,
+    Error = 3,
+    // nothing is sent
+    Off = 4,
+}
+
+#[derive(Clone, Copy)]
+struct Line {
+    level: Level,
+    text: [u8; LINE_SIZE],
+    len: usize,
+}
+
+impl Line {
+    const fn new(level: Level) -> Self {
+        Self {
+            level,
+            text: [0; LINE_SIZE],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.text[..self.len]).unwrap_or("")
+    }
+}
+
+// truncates on a char boundary
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.len + c.len_utf8() > LINE_SIZE {
+                break;
+            }
+            self.len += c.encode_utf8(&mut self.text[self.len..]).len();
+        }
+        Ok(())
+    }
+}
+
+struct Ring {
+    lines: [Line; BUFFER_LINES],
+    start: usize,
+    len: usize,
+}
+
+static LEVEL: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Level>> =
+    blocking_mutex::Mutex::new(Cell::new(Level::Info));
+static RING: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<Ring>> =
+    blocking_mutex::Mutex::new(RefCell::new(Ring {
+        lines: [Line::new(Level::Off); BUFFER_LINES],
+        start: 0,
+        len: 0,
+    }));
+static PENDING: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn set_level(level: Level) {
+    LEVEL.lock(|l| l.set(level));
+}
+
+pub fn level() -> Level {
+    LEVEL.lock(Cell::get)
+}
+
+// always goes to defmt, to the network only at or above the set level
+pub fn log(level: Level, args: fmt::Arguments) {
+    let mut line = Line::new(level);
+    let _ = line.write_fmt(args);
+    match level {
+        Level::Debug => defmt::debug!("{=str}", line.as_str()),
+        Level::Info => defmt::info!("{=str}", line.as_str()),
+        Level::Warn => defmt::warn!("{=str}", line.as_str()),
+        Level::Error | Level::Off => defmt::error!("{=str}", line.as_str()),
+    }
+    if level < self::level() {
+        return;
+    }
+
+    RING.lock(|r| {
+        let mut r = r.borrow_mut();
+        let index = (r.start + r.len) % BUFFER_LINES;
+        r.lines[index] = line;
+        if r.len == BUFFER_LINES {
+            r.start = (r.start + 1) % BUFFER_LINES;
+        } else {
+            r.len += 1;
+        }
+    });
+    PENDING.signal(());
+}
+
+fn pop() -> Option<Line> {
+    RING.lock(|r| {
+        let mut r = r.borrow_mut();
+        if r.len == 0 {
+            return None;
+        }
+        let line = r.lines[r.start];
+        r.start = (r.start + 1) % BUFFER_LINES;
+        r.len -= 1;
+        Some(line)
+    })
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::netlog::log($crate::netlog::Level::Debug, format_args!($($arg)*)) };
+}
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::netlog::log($crate::netlog::Level::Info, format_args!($($arg)*)) };
+}
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::netlog::log($crate::netlog::Level::Warn, format_args!($($arg)*)) };
+}
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::netlog::log($crate::netlog::Level::Error, format_args!($($arg)*)) };
+}
+pub(crate) use {debug, error, info, warn};
+
+// sends the buffered lines as rfc 5424 syslog messages to the server
+#[embassy_executor::task]
+pub async fn syslog_task(stack: &'static Stack<NetDriver<'static>>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 64];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    while socket.bind(0).is_err() {
+        Timer::after(Duration::from_secs(1)).await;
+    }
+
+    let collector = IpEndpoint::new(SERVER_IP, SYSLOG_PORT);
+    loop {
+        PENDING.wait().await;
+        while !stack.is_config_up() {
+            Timer::after(Duration::from_secs(1)).await;
+        }
+
+        while let Some(line) = pop() {
+            let severity = match line.level {
+                Level::Debug => 7,
+                Level::Info => 6,
+                Level::Warn => 4,
+                Level::Error | Level::Off => 3,
+            };
+            let mut message = Line::new(line.level);
+            let mut packet = [0; 64 + LINE_SIZE];
+            let _ = write!(
+                message,
+                "<{}>1 - {} pico_filter - - - ",
+                SYSLOG_FACILITY * 8 + severity,
+                ID
+            );
+            let header = message.len.min(64);
+            packet[..header].copy_from_slice(&message.text[..header]);
+            packet[header..header + line.len].copy_from_slice(&line.text[..line.len]);
+            if socket
+                .send_to(&packet[..header + line.len], collector)
+                .await
+                .is_err()
+            {
+                defmt::debug!("syslog send failed");
+            }
+        }
+    }
+}
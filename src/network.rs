@@ -1,16 +1,26 @@
+use core::cell::Cell;
+
 use cyw43::{Control, NetDriver};
 use defmt::debug;
-use defmt::{info, warn, Format};
+use defmt::Format;
 use embassy_net::tcp::TcpSocket;
 use embassy_net::Stack;
-use embassy_time::{Duration, Timer};
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
 
 use crate::ID;
+use crate::crash;
 use crate::messages;
+use crate::netlog;
 use crate::messages::ForceState;
 use crate::messages::Message;
 use crate::messages::MessagePayload;
 use crate::messages::Register;
+use crate::ota;
+use crate::reset;
+use crate::supervisor;
+use crate::storage;
 use crate::FIRMWARE_VERSION;
 use crate::STATE;
 use crate::TOKEN;
@@ -21,11 +31,39 @@ use crate::SERVER_PORT;
 use crate::WIFI_NETWORK;
 use crate::WIFI_PASSWORD;
 
+const STATISTICS_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// a read or write that takes longer fails instead of blocking the task
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(30);
+// a chunk is requested again this often before the update is given up
+const CHUNK_RETRIES: u8 = 3;
+
+// credentials set at runtime, the built in ones are used while None
+static WIFI_CREDENTIALS: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Option<state::WifiCredentials>>> =
+    blocking_mutex::Mutex::new(Cell::new(None));
+// leave the network and join again with the current credentials
+static REJOIN: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn set_wifi_credentials(credentials: state::WifiCredentials) {
+    WIFI_CREDENTIALS.lock(|c| c.set(Some(credentials)));
+}
+
+pub fn rejoin() {
+    REJOIN.signal(());
+}
+
 async fn join_network(control: &mut Control<'static>) -> bool {
-    match control.join_wpa2(WIFI_NETWORK, WIFI_PASSWORD).await {
+    let result = match WIFI_CREDENTIALS.lock(Cell::get) {
+        Some(credentials) => {
+            control
+                .join_wpa2(credentials.ssid(), credentials.password())
+                .await
+        }
+        None => control.join_wpa2(WIFI_NETWORK, WIFI_PASSWORD).await,
+    };
+    match result {
         Ok(()) => true,
         Err(err) => {
-            info!("join failed with status={}", err.status);
+            netlog::info!("join failed with status={}", err.status);
             false
         }
     }
@@ -35,31 +73,47 @@ async fn join_network(control: &mut Control<'static>) -> bool {
 pub async fn start_network(
     mut control: Control<'static>,
     stack: &'static Stack<NetDriver<'static>>,
+    mut ota: ota::Ota,
 ) -> ! {
     loop {
         // join wifi network
         while !join_network(&mut control).await {
+            supervisor::check_in(supervisor::Task::Network);
             Timer::after(Duration::from_secs(1)).await;
         }
+        STATE.lock().await.network_stats.wifi_joins += 1;
 
         // Wait for DHCP
         while !stack.is_config_up() {
+            supervisor::check_in(supervisor::Task::Network);
             Timer::after(Duration::from_millis(100)).await;
         }
         let local_addr = stack.config_v4().unwrap().address.address();
-        info!("IP address: {:?}", local_addr);
+        netlog::info!("IP address: {}", local_addr);
 
         let mut rx_buffer = [0; 4096];
         let mut tx_buffer = [0; 4096];
 
         let server_endpoint = embassy_net::IpEndpoint::new(SERVER_IP, SERVER_PORT);
+        let mut last_statistics: Option<Instant> = None;
 
         loop {
+            supervisor::check_in(supervisor::Task::Network);
+            if REJOIN.try_take().is_some() {
+                netlog::info!("joining with new credentials");
+                control.leave().await;
+                let mut c = STATE.lock().await;
+                c.network_state = state::NetworkState::Disconnected;
+                c.network_stats.rssi = None;
+                drop(c);
+                break;
+            }
             let mut socket =
                 embassy_net::tcp::TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+            socket.set_timeout(Some(SOCKET_TIMEOUT));
             // connect to server
             if let Err(e) = socket.connect(server_endpoint).await {
-                warn!("connect error: {}", e);
+                netlog::warn!("connect error: {:?}", e);
                 STATE.lock().await.network_state = state::NetworkState::Disconnected;
                 Timer::after(Duration::from_secs(1)).await;
                 continue;
@@ -70,17 +124,58 @@ pub async fn start_network(
             match network_state {
                 state::NetworkState::Disconnected => {
                     if let Err(e) = try_register(&mut socket).await {
-                        warn!("register error: {}", e);
+                        netlog::warn!("register error: {:?}", e);
                         Timer::after(Duration::from_secs(1)).await;
                         continue;
                     }
+                    STATE.lock().await.network_stats.registrations += 1;
+                    ota.confirm();
+                    if let Err(e) = try_crash_report(&mut socket).await {
+                        netlog::warn!("crash report error: {:?}", e);
+                    }
                 }
                 state::NetworkState::Registered => {
-                    if let Err(e) = try_heartbeat(&mut socket).await {
-                        warn!("heartbeat error: {}", e);
-                        STATE.lock().await.network_state = state::NetworkState::Disconnected;
-                        Timer::after(Duration::from_secs(1)).await;
-                        continue;
+                    let action = match try_heartbeat(&mut socket).await {
+                        Ok(action) => action,
+                        Err(e) => {
+                            netlog::warn!("heartbeat error: {:?}", e);
+                            STATE.lock().await.network_state = state::NetworkState::Disconnected;
+                            Timer::after(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                    };
+                    let rssi = control.get_rssi().await;
+                    STATE.lock().await.network_stats.rssi = Some(rssi);
+                    match action {
+                        None => {}
+                        Some(Action::Firmware(firmware)) => {
+                            match try_firmware_update(&mut socket, &mut ota, firmware).await {
+                                Ok(()) => {
+                                    netlog::info!("restarting into new firmware");
+                                    reset::prepare_shutdown().await;
+                                    reset::reset(state::ResetReason::FirmwareUpdate);
+                                }
+                                Err(e) => {
+                                    netlog::warn!("firmware update error: {:?}", e);
+                                    ota.abort();
+                                }
+                            }
+                        }
+                        Some(Action::Reset) => {
+                            reset::prepare_shutdown().await;
+                            if let Err(e) = send_reset_ack(&mut socket).await {
+                                netlog::warn!("reset ack error: {:?}", e);
+                            }
+                            socket.close();
+                            let _ = socket.flush().await;
+                            reset::reset(state::ResetReason::Command);
+                        }
+                    }
+                    if last_statistics.map_or(true, |t| t.elapsed() > STATISTICS_INTERVAL) {
+                        match try_statistics(&mut socket).await {
+                            Ok(()) => last_statistics = Some(Instant::now()),
+                            Err(e) => netlog::warn!("statistics error: {:?}", e),
+                        }
                     }
                 }
             }
@@ -89,14 +184,23 @@ pub async fn start_network(
     }
 }
 
-#[derive(Format)]
+#[derive(Format, Debug)]
 enum NetworkError {
     MessageError(&'static str),
     ReadError,
     WrongMessageType,
+    Ota(ota::OtaError),
+    BadChunk,
+}
+
+// commands that need the connection after the heartbeat
+enum Action {
+    Firmware(messages::NewFirmware),
+    Reset,
 }
 
-async fn try_heartbeat(socket: &mut TcpSocket<'_>) -> Result<(), NetworkError> {
+async fn try_heartbeat(socket: &mut TcpSocket<'_>) -> Result<Option<Action>, NetworkError> {
+    let mut action = None;
 
     // create heartbeat message
     let state = STATE.lock().await;
@@ -107,7 +211,7 @@ async fn try_heartbeat(socket: &mut TcpSocket<'_>) -> Result<(), NetworkError> {
     match send_message(socket, MessagePayload::Heartbeat(heartbeat)).await {
         Ok(()) => debug!("sent heartbeat message"),
         Err(e) => {
-            warn!("send error: {}", e);
+            netlog::warn!("send error: {:?}", e);
             Timer::after(Duration::from_secs(1)).await;
             return Err(e);
         }
@@ -123,57 +227,180 @@ async fn try_heartbeat(socket: &mut TcpSocket<'_>) -> Result<(), NetworkError> {
         match resp.command {
             CommandType::None => {},
             CommandType::ForceState(ForceState{state: 0, time}) => {
-                info!("forced idle: {} ms", time);
+                netlog::info!("forced idle: {} ms", time);
                 state.state.queued_state = Some(state::FilterState::ForcedIdle(time));
             },
             CommandType::ForceState(ForceState{state: 1, time}) => {
-                info!("forced clean: {} ms", time);
+                netlog::info!("forced clean: {} ms", time);
                 state.state.queued_state = Some(state::FilterState::ForcedClean(time));
             },
             CommandType::ForceState(ForceState{state: 2, time}) => {
-                info!("forced fill: {} ms", time);
+                netlog::info!("forced fill: {} ms", time);
                 state.state.queued_state = Some(state::FilterState::ForcedFill(time));
             },
             CommandType::ForceState(_) => {
-                warn!("got invalid force state command");
+                netlog::warn!("got invalid force state command");
             },
             CommandType::ResyncTime(time) => {
-                info!("resync time");
+                netlog::info!("resync time");
                 state.clock_skew = time.time - embassy_time::Instant::now().as_millis();
             },
             CommandType::UpdateConfig(conf) => {
-                info!("got config update");
-                state.config = state::Config {
-                    waterlevel_fill_start: conf.waterlevel_fill_start,
-                    waterlevel_fill_end: conf.waterlevel_fill_end,
-                    clean_before_fill_duration: conf.clean_before_fill_duration,
-                    clean_after_fill_duration: conf.clean_after_fill_duration,
-                    leak_protection: conf.leak_protection == 1,
-                };
+                netlog::info!("got config update");
+                apply_config(&mut state.config, &conf);
             },
             CommandType::SetResetLeak(leak) => {
                 if leak.leak == 1 {
-                    info!("got set leak");
+                    netlog::info!("got set leak");
                     state.state.leak = Some(embassy_time::Instant::now().as_millis());
                 } else {
-                    info!("got reset leak");
+                    netlog::info!("got reset leak");
                     state.state.leak = None;
                 }
+                state.state.leak_probe = None;
             },
             CommandType::ResetMeasurementError => {
-                info!("got reset measurement error");
+                netlog::info!("got reset measurement error");
                 state.state.measurement_error = None;
             },
-            CommandType::NewFirmware(_) => warn!("got new firmware command: Unimplemented"),
+            CommandType::NewFirmware(new_firmware) => {
+                netlog::info!("got new firmware version {}", new_firmware.version);
+                action = Some(Action::Firmware(new_firmware));
+            }
             CommandType::ResetDevice => {
-                info!("got reset device: Unimplemented");
+                netlog::info!("got reset device");
+                action = Some(Action::Reset);
+            }
+            CommandType::UpdateValveMapping(mapping) => {
+                netlog::info!("got valve mapping update");
+                if usize::from(mapping.count) > crate::VALVE_COUNT {
+                    netlog::warn!("valve mapping uses {} valves, only {} connected", mapping.count, crate::VALVE_COUNT);
+                }
+                state.config.valves = state::ValveConfig {
+                    count: mapping.count.min(crate::VALVE_COUNT as u8),
+                    idle: mapping.idle,
+                    clean: mapping.clean,
+                    fill: mapping.fill,
+                    dead_time: mapping.dead_time,
+                    stagger: mapping.stagger,
+                    ..state.config.valves
+                };
+            }
+            CommandType::ResetValveFault => {
+                netlog::info!("got reset valve fault");
+                state.state.valve_fault = None;
+            }
+            CommandType::ResetPumpFault => {
+                netlog::info!("got reset pump fault");
+                state.state.pump_fault = None;
+            }
+            CommandType::ResetFlowFault => {
+                netlog::info!("got reset flow fault");
+                state.state.flow_fault = None;
+            }
+            CommandType::SetLogLevel(level) => {
+                let level = match level {
+                    0 => netlog::Level::Debug,
+                    1 => netlog::Level::Info,
+                    2 => netlog::Level::Warn,
+                    3 => netlog::Level::Error,
+                    _ => netlog::Level::Off,
+                };
+                netlog::info!("got set log level {:?}", level);
+                netlog::set_level(level);
             }
         }
     } else {
-        warn!("wrong message type");
+        netlog::warn!("wrong message type");
         return Err(NetworkError::WrongMessageType);
     }
 
+    Ok(action)
+}
+
+// downloads the image into the update partition, chunk by chunk over the open connection
+async fn try_firmware_update(
+    socket: &mut TcpSocket<'_>,
+    ota: &mut ota::Ota,
+    firmware: messages::NewFirmware,
+) -> Result<(), NetworkError> {
+    let version = firmware.version;
+    ota.begin(firmware).map_err(NetworkError::Ota)?;
+    let mut id = [0; 32];
+    id.copy_from_slice(ID.as_bytes());
+
+    let mut offset = 0;
+    let mut retries = 0;
+    while let Some(remaining) = ota.remaining().filter(|r| *r > 0) {
+        supervisor::check_in(supervisor::Task::Network);
+        let length = remaining.min(ota::CHUNK_SIZE as u64) as u16;
+        let request = messages::FirmwareRequest {
+            dev_id: id,
+            version,
+            offset,
+            length,
+        };
+        send_message(socket, MessagePayload::FirmwareRequest(request)).await?;
+
+        let message = recv_message(socket).await?;
+        let MessagePayload::FirmwareChunk(chunk) = message.payload else {
+            netlog::warn!("wrong message type");
+            return Err(NetworkError::WrongMessageType);
+        };
+        let data = &chunk.data[..usize::from(chunk.length)];
+        if chunk.offset != offset || chunk.length != length || storage::crc32(data) != chunk.crc {
+            netlog::warn!("bad firmware chunk at offset {}", offset);
+            retries += 1;
+            if retries > CHUNK_RETRIES {
+                return Err(NetworkError::BadChunk);
+            }
+            continue;
+        }
+
+        ota.write(u64::from(offset), data).map_err(NetworkError::Ota)?;
+        offset += u32::from(length);
+        retries = 0;
+    }
+
+    ota.finish().await.map_err(NetworkError::Ota)
+}
+
+async fn try_statistics(socket: &mut TcpSocket<'_>) -> Result<(), NetworkError> {
+    let state = STATE.lock().await;
+    let statistics = messages::create_statistics(&state);
+    drop(state);
+
+    // the server does not answer statistics
+    send_message(socket, MessagePayload::Statistics(statistics)).await?;
+    debug!("sent statistics message");
+    Ok(())
+}
+
+// sends the crash dump from before the last reset, it stays in ram until sent
+async fn try_crash_report(socket: &mut TcpSocket<'_>) -> Result<(), NetworkError> {
+    let Some(dump) = STATE.lock().await.crash_dump else {
+        return Ok(());
+    };
+    let mut id = [0; 32];
+    id.copy_from_slice(ID.as_bytes());
+    let report = messages::CrashReport {
+        dev_id: id,
+        dump,
+    };
+
+    // the server does not answer crash reports
+    send_message(socket, MessagePayload::CrashReport(report)).await?;
+    netlog::info!("sent crash report");
+    crash::clear_dump();
+    STATE.lock().await.crash_dump = None;
+    Ok(())
+}
+
+async fn send_reset_ack(socket: &mut TcpSocket<'_>) -> Result<(), NetworkError> {
+    let mut id = [0; 32];
+    id.copy_from_slice(ID.as_bytes());
+    send_message(socket, MessagePayload::ResetAck(messages::ResetAck { dev_id: id })).await?;
+    netlog::info!("sent reset ack");
     Ok(())
 }
 
@@ -184,6 +411,17 @@ async fn try_register(socket: &mut TcpSocket<'_>) -> Result<(), NetworkError> {
     let mut id = [0; 32];
     id.copy_from_slice(ID.as_bytes());
 
+    let (reset_reason, starved_task) = match STATE.lock().await.reset_reason {
+        state::ResetReason::Unknown => (0x00, 0xff),
+        state::ResetReason::Command => (0x01, 0xff),
+        state::ResetReason::FirmwareUpdate => (0x02, 0xff),
+        state::ResetReason::Rollback => (0x03, 0xff),
+        state::ResetReason::Watchdog(task) => (0x04, task as u8),
+        state::ResetReason::Crash => (0x05, 0xff),
+        state::ResetReason::WatchdogTimeout => (0x06, 0xff),
+        state::ResetReason::Console => (0x07, 0xff),
+    };
+
     // create register message
     let register = Register {
         dev_id: id,
@@ -191,13 +429,15 @@ async fn try_register(socket: &mut TcpSocket<'_>) -> Result<(), NetworkError> {
         dev_type: 0x01,
         firmware_version: FIRMWARE_VERSION,
         needs_config: 0x01,
+        reset_reason,
+        starved_task,
     };
 
     // send register message
     match send_message(socket, MessagePayload::Register(register)).await {
-        Ok(()) => info!("sent register message"),
+        Ok(()) => netlog::info!("sent register message"),
         Err(e) => {
-            warn!("send error: {}", e);
+            netlog::warn!("send error: {:?}", e);
             Timer::after(Duration::from_secs(1)).await;
             return Err(e);
         }
@@ -206,34 +446,57 @@ async fn try_register(socket: &mut TcpSocket<'_>) -> Result<(), NetworkError> {
     // read response
     let message = recv_message(socket).await?;
     if let MessagePayload::Accepted(acc) = message.payload {
-        info!("registration accepted");
+        netlog::info!("registration accepted");
         let mut state = STATE.lock().await;
         state.clock_skew = acc.time - embassy_time::Instant::now().as_millis();
         if let Some(conf) = acc.config {
-            info!("got config while registering");
-            state.config = state::Config {
-                waterlevel_fill_start: conf.waterlevel_fill_start,
-                waterlevel_fill_end: conf.waterlevel_fill_end,
-                clean_before_fill_duration: conf.clean_before_fill_duration,
-                clean_after_fill_duration: conf.clean_after_fill_duration,
-                leak_protection: conf.leak_protection == 1,
-            };
+            netlog::info!("got config while registering");
+            apply_config(&mut state.config, &conf);
         }
         state.network_state = state::NetworkState::Registered;
     } else {
-        warn!("wrong message type");
+        netlog::warn!("wrong message type");
         return Err(NetworkError::WrongMessageType);
     }
     Ok(())
 }
 
+// only overwrite the values the server manages
+fn apply_config(config: &mut state::Config, conf: &messages::Config) {
+    config.waterlevel_fill_start = conf.waterlevel_fill_start;
+    config.waterlevel_fill_end = conf.waterlevel_fill_end;
+    config.clean_before_fill_duration = conf.clean_before_fill_duration;
+    config.clean_after_fill_duration = conf.clean_after_fill_duration;
+    config.leak_protection = conf.leak_protection == 1;
+    config.threshold_unit = match conf.threshold_unit {
+        1 => state::ThresholdUnit::Percent,
+        _ => state::ThresholdUnit::Distance,
+    };
+    // unknown values keep the policy the device has
+    config.recovery = match conf.recovery_policy {
+        0 => state::RecoveryPolicy::Abort,
+        1 => state::RecoveryPolicy::Resume,
+        _ => config.recovery,
+    };
+}
+
 async fn recv_message(socket: &mut TcpSocket<'_>) -> Result<Message, NetworkError> {
     let mut buf = [0; 4096];
-    match socket.read(&mut buf).await {
-        Ok(n) => (),
-        Err(e) => {
-            warn!("read error: {}", e);
-            return Err(NetworkError::ReadError);
+    // larger messages like firmware chunks can arrive in several segments
+    let mut received = 0;
+    let mut length = 9;
+    while received < length {
+        match socket.read(&mut buf[received..]).await {
+            Ok(0) => return Err(NetworkError::ReadError),
+            Ok(n) => received += n,
+            Err(e) => {
+                netlog::warn!("read error: {:?}", e);
+                return Err(NetworkError::ReadError);
+            }
+        }
+        if received >= 9 {
+            length = (u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]) as usize)
+                .clamp(10, buf.len());
         }
     }
 
@@ -251,7 +514,7 @@ async fn send_message(
     match socket.write(&buf[0..len]).await {
         Ok(_n) => (),
         Err(e) => {
-            warn!("write error: {}", e);
+            netlog::warn!("write error: {:?}", e);
             return Err(NetworkError::ReadError);
         }
     }
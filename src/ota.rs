@@ -0,0 +1,207 @@
+use core::cell::Cell;
+
+use defmt::Format;
+use embassy_boot_rp::{BlockingFirmwareUpdater, FirmwareUpdaterConfig, State as BootState};
+use embassy_embedded_hal::flash::partition::BlockingPartition;
+use embassy_rp::flash::{Blocking, Flash};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::blocking_mutex;
+use embassy_time::{Duration, Timer};
+use embedded_storage::nor_flash::ReadNorFlash;
+use salty::{PublicKey, Signature};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::messages::NewFirmware;
+use crate::netlog;
+use crate::reset;
+use crate::state::ResetReason;
+use crate::storage::{SharedFlash, FLASH_SIZE};
+
+// payload size of one firmware chunk message
+pub const CHUNK_SIZE: usize = 1024;
+// the updater erases and writes whole sectors
+const PAGE_SIZE: usize = 4096;
+// size of FLASH in memory.x, DFU is one page larger for the swap
+const MAX_FIRMWARE_SIZE: u64 = 960 * 1024;
+// release key the images have to be signed with, see tools/sign_firmware.py
+static PUBLIC_KEY: &[u8; 32] = include_bytes!(concat!(env!("OUT_DIR"), "/firmware_key.pub"));
+// a new image that does not register in time is rolled back on the next boot
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// running an image that was swapped in and not yet confirmed, shared with the rollback task
+static CONFIRM_PENDING: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<bool>> =
+    blocking_mutex::Mutex::new(Cell::new(false));
+
+type Partition = BlockingPartition<
+    'static,
+    NoopRawMutex,
+    Flash<'static, FLASH, Blocking, FLASH_SIZE>,
+>;
+
+#[derive(Format, Debug)]
+pub enum OtaError {
+    TooLarge,
+    NotStarted,
+    WrongOffset,
+    Flash,
+    HashMismatch,
+    BadSignature,
+}
+
+struct Download {
+    firmware: NewFirmware,
+    received: u64,
+    page: [u8; PAGE_SIZE],
+}
+
+pub struct Ota {
+    updater: BlockingFirmwareUpdater<'static, Partition, Partition>,
+    // second view of DFU to read the image back for the verification
+    dfu: Partition,
+    download: Option<Download>,
+}
+
+impl Ota {
+    // aligned has to hold one write unit of the state partition
+    pub fn new(flash: &'static SharedFlash, aligned: &'static mut [u8]) -> Self {
+        let config = FirmwareUpdaterConfig::from_linkerfile_blocking(flash);
+        let dfu = FirmwareUpdaterConfig::from_linkerfile_blocking(flash).dfu;
+        let mut updater = BlockingFirmwareUpdater::new(config, aligned);
+        let confirm_pending = matches!(updater.get_state(), Ok(BootState::Swap));
+        if confirm_pending {
+            netlog::info!("running new firmware, waiting for registration");
+        }
+        CONFIRM_PENDING.lock(|c| c.set(confirm_pending));
+
+        Self {
+            updater,
+            dfu,
+            download: None,
+        }
+    }
+
+    // called after the server accepted the registration
+    pub fn confirm(&mut self) {
+        if !CONFIRM_PENDING.lock(Cell::get) {
+            return;
+        }
+        match self.updater.mark_booted() {
+            Ok(()) => {
+                netlog::info!("new firmware confirmed");
+                CONFIRM_PENDING.lock(|c| c.set(false));
+            }
+            Err(_) => netlog::warn!("failed to confirm firmware"),
+        }
+    }
+
+    pub fn begin(&mut self, firmware: NewFirmware) -> Result<(), OtaError> {
+        if firmware.size == 0 || firmware.size > MAX_FIRMWARE_SIZE {
+            return Err(OtaError::TooLarge);
+        }
+        netlog::info!(
+            "starting firmware download: version {}, {} bytes",
+            firmware.version, firmware.size
+        );
+        self.download = Some(Download {
+            firmware,
+            received: 0,
+            page: [0xff; PAGE_SIZE],
+        });
+        Ok(())
+    }
+
+    // bytes still missing, None if no download is running
+    pub fn remaining(&self) -> Option<u64> {
+        self.download
+            .as_ref()
+            .map(|d| d.firmware.size - d.received)
+    }
+
+    // chunks have to arrive in order, full pages are written to DFU
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), OtaError> {
+        let download = self.download.as_mut().ok_or(OtaError::NotStarted)?;
+        if offset != download.received || offset + data.len() as u64 > download.firmware.size {
+            return Err(OtaError::WrongOffset);
+        }
+
+        let mut data = data;
+        while !data.is_empty() {
+            let start = (download.received % PAGE_SIZE as u64) as usize;
+            let len = data.len().min(PAGE_SIZE - start);
+            download.page[start..start + len].copy_from_slice(&data[..len]);
+            download.received += len as u64;
+            data = &data[len..];
+
+            if start + len == PAGE_SIZE || download.received == download.firmware.size {
+                let page_offset = (download.received - 1) / PAGE_SIZE as u64 * PAGE_SIZE as u64;
+                let result = self
+                    .updater
+                    .write_firmware(page_offset as usize, &download.page);
+                download.page = [0xff; PAGE_SIZE];
+                if result.is_err() {
+                    self.download = None;
+                    return Err(OtaError::Flash);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // verifies the written image and its signature, then marks it for the swap on the next boot
+    pub async fn finish(&mut self) -> Result<(), OtaError> {
+        let download = self.download.take().ok_or(OtaError::NotStarted)?;
+        if download.received != download.firmware.size {
+            return Err(OtaError::WrongOffset);
+        }
+
+        // one pass gives the sha256 the server announced and the sha512 the signature covers.
+        // hashing the whole image takes seconds, so the task yields after every chunk to keep
+        // the others and the watchdog going
+        let mut chunk = [0; CHUNK_SIZE];
+        let mut sha256 = Sha256::new();
+        let mut sha512 = Sha512::new();
+        let mut offset = 0;
+        while offset < download.firmware.size {
+            let len = (download.firmware.size - offset).min(CHUNK_SIZE as u64) as usize;
+            self.dfu
+                .read(offset as u32, &mut chunk[..len])
+                .map_err(|_| OtaError::Flash)?;
+            sha256.update(&chunk[..len]);
+            sha512.update(&chunk[..len]);
+            offset += len as u64;
+            Timer::after(Duration::from_millis(1)).await;
+        }
+        if sha256.finalize()[..] != download.firmware.sha256 {
+            return Err(OtaError::HashMismatch);
+        }
+
+        let public_key = PublicKey::try_from(PUBLIC_KEY).map_err(|_| OtaError::BadSignature)?;
+        let signature = Signature::from(&download.firmware.signature);
+        public_key
+            .verify(&sha512.finalize(), &signature)
+            .map_err(|_| OtaError::BadSignature)?;
+        self.updater.mark_updated().map_err(|_| OtaError::Flash)?;
+        netlog::info!("firmware version {} verified", download.firmware.version);
+        Ok(())
+    }
+
+    pub fn abort(&mut self) {
+        self.download = None;
+    }
+}
+
+// gives up an unconfirmed image, runs on its own so a network that never comes up
+// still leads to the rollback
+#[embassy_executor::task]
+pub async fn rollback_task() {
+    if !CONFIRM_PENDING.lock(Cell::get) {
+        return;
+    }
+    Timer::after(CONFIRM_TIMEOUT).await;
+    if CONFIRM_PENDING.lock(Cell::get) {
+        netlog::warn!("new firmware did not register, rolling back");
+        reset::prepare_shutdown().await;
+        reset::reset(ResetReason::Rollback);
+    }
+}
@@ -0,0 +1,104 @@
+use embassy_rp::gpio::{AnyPin, Output};
+
+use crate::state::{FilterState, PumpConfig};
+
+pub struct Pump {
+    pin: Output<'static, AnyPin>,
+    running: bool,
+    // earliest time the pump may start after its path opened
+    start_at: Option<u64>,
+    // time the run on after the demand ended is over
+    stop_at: Option<u64>,
+    // (time, waterlevel) the last level rise was measured from
+    dry_run_reference: Option<(u64, u64)>,
+}
+
+impl Pump {
+    pub fn new(mut pin: Output<'static, AnyPin>) -> Self {
+        pin.set_low();
+        Self {
+            pin,
+            running: false,
+            start_at: None,
+            stop_at: None,
+            dry_run_reference: None,
+        }
+    }
+
+    pub const fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn stop(&mut self) {
+        self.pin.set_low();
+        self.running = false;
+        self.start_at = None;
+        self.stop_at = None;
+        self.dry_run_reference = None;
+    }
+
+    // keeps the pump going on the old path for run_on ms, true once it has to stop
+    pub fn run_on_over(&mut self, run_on: u64) -> bool {
+        let now = embassy_time::Instant::now().as_millis();
+        now >= *self.stop_at.get_or_insert(now + run_on)
+    }
+
+    // true if the pump should run in this state
+    pub const fn demanded(config: &PumpConfig, filter_state: FilterState) -> bool {
+        if !config.enabled {
+            return false;
+        }
+        match filter_state {
+            FilterState::Fill | FilterState::ForcedFill(_) => config.fill,
+            FilterState::CleanBeforeFill
+            | FilterState::CleanAfterFill
+            | FilterState::ForcedClean(_) => config.clean,
+            FilterState::Idle | FilterState::ForcedIdle(_) => false,
+        }
+    }
+
+    // path_open is the interlock, the pump never runs against closed valves.
+    // returns true if the pump was stopped because the level did not rise
+    pub fn update(
+        &mut self,
+        config: &PumpConfig,
+        demand: bool,
+        path_open: bool,
+        filling: bool,
+        waterlevel: Option<u64>,
+    ) -> bool {
+        if !demand || !path_open {
+            self.stop();
+            return false;
+        }
+
+        self.stop_at = None;
+        let now = embassy_time::Instant::now().as_millis();
+        if !self.running {
+            let start_at = *self.start_at.get_or_insert(now + config.start_delay);
+            if now >= start_at {
+                self.pin.set_high();
+                self.running = true;
+            }
+            return false;
+        }
+
+        // while filling the distance to the water has to shrink
+        if !filling {
+            return false;
+        }
+        let Some(level) = waterlevel else {
+            return false;
+        };
+        let (since, reference) = *self.dry_run_reference.get_or_insert((now, level));
+        if now - since < config.dry_run_timeout {
+            return false;
+        }
+        if reference.saturating_sub(level) < config.dry_run_min_rise {
+            self.stop();
+            return true;
+        }
+        self.dry_run_reference = Some((now, level));
+        false
+    }
+}
@@ -0,0 +1,89 @@
+use embassy_time::{Duration, Instant};
+
+use crate::netlog;
+use crate::state::{FilterState, Recovery, RecoveryAction, RecoveryPolicy, ResetReason, State};
+use crate::storage::{JournalEntry, Storage};
+
+// progress of a running cycle is journaled this often, state changes right away
+const JOURNAL_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Journal {
+    storage: Storage,
+    // filter state and last_state_change of the last entry
+    last: Option<(FilterState, u64)>,
+    last_write: Instant,
+}
+
+impl Journal {
+    pub fn new(storage: Storage) -> Self {
+        Self {
+            storage,
+            last: None,
+            last_write: Instant::now(),
+        }
+    }
+
+    pub fn update(&mut self, filter_state: FilterState, last_state_change: u64) {
+        let changed = self.last != Some((filter_state, last_state_change));
+        let active = filter_state != FilterState::Idle;
+        if !changed && !(active && self.last_write.elapsed() > JOURNAL_INTERVAL) {
+            return;
+        }
+
+        let entry = JournalEntry {
+            filter_state,
+            elapsed: Instant::now().as_millis() - last_state_change,
+        };
+        if let Err(e) = self.storage.append_journal(&entry) {
+            netlog::warn!("writing journal failed: {:?}", e);
+        }
+        self.last = Some((filter_state, last_state_change));
+        self.last_write = Instant::now();
+    }
+}
+
+// continues the cycle that was running before the reset or deliberately drops it
+pub fn recover(
+    state: &mut State,
+    entry: Option<JournalEntry>,
+    policy: RecoveryPolicy,
+    reset_reason: ResetReason,
+) {
+    let Some(entry) = entry else {
+        return;
+    };
+    if entry.filter_state == FilterState::Idle {
+        return;
+    }
+
+    // resuming could run straight into the same crash again
+    let action = match (reset_reason, policy) {
+        (ResetReason::Crash | ResetReason::Watchdog(_) | ResetReason::WatchdogTimeout, _) => {
+            RecoveryAction::AbortedCrash
+        }
+        (_, RecoveryPolicy::Abort) => RecoveryAction::AbortedPolicy,
+        (_, RecoveryPolicy::Resume) => RecoveryAction::Resumed,
+    };
+    netlog::info!(
+        "interrupted in {:?} after {} ms: {:?}",
+        entry.filter_state, entry.elapsed, action
+    );
+
+    if action == RecoveryAction::Resumed {
+        state.filter_state = match entry.filter_state {
+            FilterState::ForcedFill(time) => FilterState::ForcedFill(time.saturating_sub(entry.elapsed)),
+            FilterState::ForcedClean(time) => {
+                FilterState::ForcedClean(time.saturating_sub(entry.elapsed))
+            }
+            FilterState::ForcedIdle(time) => FilterState::ForcedIdle(time.saturating_sub(entry.elapsed)),
+            // cleaning starts over, filling stops by the level anyway
+            other => other,
+        };
+        state.last_state_change = Instant::now().as_millis();
+    }
+    state.recovery = Some(Recovery {
+        filter_state: entry.filter_state,
+        elapsed: entry.elapsed,
+        action,
+    });
+}
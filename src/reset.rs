@@ -0,0 +1,83 @@
+use core::mem::MaybeUninit;
+use core::ptr::addr_of_mut;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration};
+
+use crate::crash;
+use crate::state::ResetReason;
+use crate::supervisor;
+
+// network task asks the state task to bring the outputs into a safe state
+pub static SHUTDOWN_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// state task is done, valves are closed and statistics are flushed
+pub static SHUTDOWN_DONE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// reset anyway if the state task hangs
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+const RECORD_MAGIC: u32 = 0x5253_5452;
+
+// magic, reason, detail. survives sys_reset and watchdog resets because the runtime
+// does not initialize .uninit and the bootloader only uses the upper half of ram
+#[link_section = ".uninit.RESET_RECORD"]
+static mut RESET_RECORD: MaybeUninit<[u32; 3]> = MaybeUninit::uninit();
+
+pub async fn prepare_shutdown() {
+    SHUTDOWN_REQUEST.signal(());
+    if with_timeout(SHUTDOWN_TIMEOUT, SHUTDOWN_DONE.wait())
+        .await
+        .is_err()
+    {
+        defmt::warn!("state task did not shut down in time");
+    }
+}
+
+pub fn reset(reason: ResetReason) -> ! {
+    record(reason);
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+pub fn record(reason: ResetReason) {
+    let (reason, detail) = match reason {
+        ResetReason::Unknown => (0, 0),
+        ResetReason::Command => (1, 0),
+        ResetReason::FirmwareUpdate => (2, 0),
+        ResetReason::Rollback => (3, 0),
+        ResetReason::Watchdog(task) => (4, task as u32),
+        ResetReason::Crash => (5, 0),
+        ResetReason::WatchdogTimeout => (6, 0),
+        ResetReason::Console => (7, 0),
+    };
+    // SAFETY: plain words written without a reference, nothing else touches the record
+    unsafe {
+        addr_of_mut!(RESET_RECORD)
+            .cast::<[u32; 3]>()
+            .write_volatile([RECORD_MAGIC, reason, detail]);
+    }
+}
+
+// reason of the last reset, cleared so a following power cycle reads as unknown
+pub fn take_reason() -> ResetReason {
+    // SAFETY: read once at boot before any task can record a reason
+    let [magic, reason, detail] =
+        unsafe { addr_of_mut!(RESET_RECORD).cast::<[u32; 3]>().read_volatile() };
+    record(ResetReason::Unknown);
+    let recorded = match reason {
+        _ if magic != RECORD_MAGIC => None,
+        1 => Some(ResetReason::Command),
+        2 => Some(ResetReason::FirmwareUpdate),
+        3 => Some(ResetReason::Rollback),
+        4 => supervisor::TASKS
+            .get(detail as usize)
+            .map(|task| ResetReason::Watchdog(*task)),
+        5 => Some(ResetReason::Crash),
+        7 => Some(ResetReason::Console),
+        _ => None,
+    };
+    // a hung executor can not record anything, the hardware still knows
+    recorded.unwrap_or(match crash::reset_cause() {
+        crash::ResetCause::WatchdogTimeout => ResetReason::WatchdogTimeout,
+        _ => ResetReason::Unknown,
+    })
+}
@@ -1,39 +1,344 @@
 use defmt::Format;
 
+use crate::crash;
+use crate::supervisor;
+use crate::tank;
+use crate::valve::MAX_VALVES;
+
 #[derive(Format)]
 pub struct Context {
     pub state: State,
     pub config: Config,
     pub network_state: NetworkState,
+    pub network_stats: NetworkStats,
     pub clock_skew: u64,
+    pub valve_stats: [ValveStats; MAX_VALVES],
+    pub reset_reason: ResetReason,
+    // reported after the next registration
+    pub crash_dump: Option<crash::CrashDump>,
 }
 
-#[derive(Format, PartialEq, Eq, Clone, Copy)]
+// why the device restarted, recorded in ram that survives the reset
+#[derive(Format, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ResetReason {
+    // power on or no recorded reason
+    Unknown,
+    Command,
+    FirmwareUpdate,
+    Rollback,
+    // the task stopped checking in with the supervisor
+    Watchdog(supervisor::Task),
+    // panic or hard fault, see the crash dump
+    Crash,
+    // the watchdog was not fed, nothing was recorded
+    WatchdogTimeout,
+    // reboot from the usb console
+    Console,
+}
+
+pub const SSID_SIZE: usize = 32;
+pub const PASSWORD_SIZE: usize = 64;
+
+// set at runtime, not Format so the password does not end up in logs
+#[derive(Clone, Copy)]
+pub struct WifiCredentials {
+    pub ssid: [u8; SSID_SIZE],
+    pub ssid_len: u8,
+    pub password: [u8; PASSWORD_SIZE],
+    pub password_len: u8,
+}
+
+impl WifiCredentials {
+    // None if a value is too long
+    pub fn new(ssid: &str, password: &str) -> Option<Self> {
+        if ssid.len() > SSID_SIZE || password.len() > PASSWORD_SIZE {
+            return None;
+        }
+        let mut credentials = Self {
+            ssid: [0; SSID_SIZE],
+            ssid_len: ssid.len() as u8,
+            password: [0; PASSWORD_SIZE],
+            password_len: password.len() as u8,
+        };
+        credentials.ssid[..ssid.len()].copy_from_slice(ssid.as_bytes());
+        credentials.password[..password.len()].copy_from_slice(password.as_bytes());
+        Some(credentials)
+    }
+
+    pub fn ssid(&self) -> &str {
+        core::str::from_utf8(&self.ssid[..usize::from(self.ssid_len)]).unwrap_or("")
+    }
+
+    pub fn password(&self) -> &str {
+        core::str::from_utf8(&self.password[..usize::from(self.password_len)]).unwrap_or("")
+    }
+}
+
+#[derive(Format, Debug, PartialEq, Eq, Clone, Copy)]
 pub enum NetworkState {
     Disconnected,
     Registered,
 }
 
+// counted since boot
+#[derive(Format, Clone, Copy)]
+pub struct NetworkStats {
+    // dBm, None while not joined
+    pub rssi: Option<i32>,
+    pub wifi_joins: u32,
+    pub registrations: u32,
+}
+
 #[derive(Format)]
 pub struct State {
     pub filter_state: FilterState,
     pub queued_state: Option<FilterState>,
     pub last_state_change: u64,
     pub waterlevel: Option<u64>,
+    pub waterlevel_raw: Option<u64>,
     pub measurement_error: Option<u64>,
+    // measurements without a single valid sample since boot
+    pub measurement_errors: u32,
     pub leak: Option<u64>,
+    // index of the probe that tripped, None if set by the server
+    pub leak_probe: Option<u8>,
+    // 0.01 °C
+    pub temperature: Option<i32>,
+    pub valve_fault: Option<ValveFault>,
+    pub last_exercise: Option<u64>,
+    pub exercise_result: Option<ExerciseResult>,
+    pub pump_running: bool,
+    // time the pump was stopped for running dry
+    pub pump_fault: Option<u64>,
+    pub flow: FlowState,
+    // time a fill without flow was detected
+    pub flow_fault: Option<u64>,
+    // cycle interrupted by the last reset
+    pub recovery: Option<Recovery>,
+}
+
+#[derive(Format, Clone, Copy)]
+pub struct Recovery {
+    pub filter_state: FilterState,
+    // ms the state had been running before the reset
+    pub elapsed: u64,
+    pub action: RecoveryAction,
+}
+
+#[derive(Format, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RecoveryAction {
+    Resumed,
+    AbortedPolicy,
+    // the reset was a crash or watchdog reset
+    AbortedCrash,
 }
 
+// volumes are kept as flow meter pulses
 #[derive(Format)]
+pub struct FlowState {
+    pub total_pulses: u64,
+    // state the current cycle is counted for
+    pub cycle_state: FilterState,
+    pub cycle_pulses: u64,
+    pub last_fill: u64,
+    pub last_clean_before: u64,
+    pub last_clean_after: u64,
+}
+
+#[derive(Format, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ExerciseResult {
+    Completed,
+    AbortedLeak,
+    AbortedLevel,
+    AbortedCommand,
+    ValveFault(ValveFault),
+}
+
+#[derive(Format, Clone, Copy)]
+pub struct ValveStats {
+    // number of times the valve was opened
+    pub actuations: u32,
+    // total ms the valve was open
+    pub open_time: u64,
+}
+
+impl ValveStats {
+    pub const fn new() -> Self {
+        Self {
+            actuations: 0,
+            open_time: 0,
+        }
+    }
+}
+
+// a valve did not reach its commanded position
+#[derive(Format, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ValveFault {
+    pub valve: u8,
+    pub expected_open: bool,
+}
+
+#[derive(Format, Clone, Copy)]
 pub struct Config {
     pub waterlevel_fill_start: u64,
     pub waterlevel_fill_end: u64,
     pub clean_before_fill_duration: u64,
     pub clean_after_fill_duration: u64,
     pub leak_protection: bool,
+    pub measurement: MeasurementConfig,
+    pub level_sensor: LevelSensorType,
+    pub tank: TankConfig,
+    pub threshold_unit: ThresholdUnit,
+    pub valves: ValveConfig,
+    pub exercise: ExerciseConfig,
+    pub pump: PumpConfig,
+    pub flow: FlowConfig,
+    pub recovery: RecoveryPolicy,
+}
+
+impl Config {
+    // fill thresholds as distance from the sensor in mm
+    pub fn fill_start_distance(&self) -> u64 {
+        self.threshold_distance(self.waterlevel_fill_start)
+    }
+
+    pub fn fill_end_distance(&self) -> u64 {
+        self.threshold_distance(self.waterlevel_fill_end)
+    }
+
+    fn threshold_distance(&self, threshold: u64) -> u64 {
+        match self.threshold_unit {
+            ThresholdUnit::Distance => threshold,
+            ThresholdUnit::Percent => tank::distance_for_percent(&self.tank, threshold),
+        }
+    }
+}
+
+// what to do with a cycle that was interrupted by a power loss
+#[derive(Format, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RecoveryPolicy {
+    Resume,
+    Abort,
+}
+
+#[derive(Format, Clone, Copy)]
+pub struct FlowConfig {
+    // raise a fault if filling without flow
+    pub enabled: bool,
+    // pulses per l
+    pub k_factor: u32,
+    // ms into a fill until at least min_pulses have to be counted
+    pub no_flow_timeout: u64,
+    pub min_pulses: u32,
+}
+
+#[derive(Format, Clone, Copy)]
+pub struct PumpConfig {
+    pub enabled: bool,
+    // run while filling
+    pub fill: bool,
+    // run while cleaning
+    pub clean: bool,
+    // ms after the valves opened before the pump starts
+    pub start_delay: u64,
+    // ms the pump keeps running before its path closes
+    pub run_on: u64,
+    // the level has to rise by dry_run_min_rise mm within dry_run_timeout ms while filling
+    pub dry_run_timeout: u64,
+    pub dry_run_min_rise: u64,
+}
+
+// cycle valves that sat closed for a long time so they don't seize
+#[derive(Format, Clone, Copy)]
+pub struct ExerciseConfig {
+    pub enabled: bool,
+    // ms in Idle before exercising, also the minimum time between exercises
+    pub idle_threshold: u64,
+    // ms each valve stays open
+    pub open_duration: u64,
+    // abort if the waterlevel moves more than this many mm
+    pub max_level_change: u64,
+}
+
+#[derive(Format, Clone, Copy)]
+pub struct ValveConfig {
+    // number of connected valves
+    pub count: u8,
+    // bit i set opens valve i
+    pub idle: u8,
+    pub clean: u8,
+    pub fill: u8,
+    // ms between closing and opening valves
+    pub dead_time: u64,
+    // ms between opening consecutive valves
+    pub stagger: u64,
+    // ms at full current before pwm driven valves drop to hold_duty
+    pub pull_in: u64,
+    // percent
+    pub hold_duty: u8,
+    // ms after a transition until the feedback is checked
+    pub feedback_delay: u64,
+}
+
+#[derive(Format, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ThresholdUnit {
+    // mm from the sensor
+    Distance,
+    // percent of the tank volume
+    Percent,
+}
+
+#[derive(Format, Clone, Copy)]
+pub struct TankConfig {
+    // distance from the sensor reference point to the tank bottom in mm
+    pub sensor_height: u64,
+    // fill height of a full tank in mm
+    pub full_height: u64,
+    pub shape: TankShape,
+}
+
+#[derive(Format, PartialEq, Eq, Clone, Copy)]
+pub enum TankShape {
+    // dimensions in mm
+    Cylinder { diameter: u64 },
+    Rectangle { width: u64, length: u64 },
+    // (fill height in mm, volume in l) sorted by height
+    Table(&'static [(u64, u64)]),
 }
 
 #[derive(Format, PartialEq, Eq, Clone, Copy)]
+pub enum LevelSensorType {
+    Ultrasonic,
+    Pressure {
+        // adc reading at zero water height
+        zero: u16,
+        // adc reading at full_scale_height
+        full_scale: u16,
+        full_scale_height: u64,
+    },
+    FloatSwitches,
+}
+
+impl LevelSensorType {
+    // discrete sensors report fixed values that must not be smoothed
+    pub const fn is_discrete(&self) -> bool {
+        matches!(self, Self::FloatSwitches)
+    }
+}
+
+#[derive(Format, Clone, Copy)]
+pub struct MeasurementConfig {
+    // number of readings taken per measurement cycle
+    pub samples: u8,
+    // largest accepted change between cycles in mm
+    pub max_jump: u64,
+    // accept a jump after this many consecutive rejections
+    pub max_rejections: u8,
+    // weight of a new reading in the moving average in percent
+    pub smoothing: u8,
+}
+
+#[derive(Format, Debug, PartialEq, Eq, Clone, Copy)]
 pub enum FilterState {
     CleanBeforeFill,
     CleanAfterFill,
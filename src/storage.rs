@@ -0,0 +1,262 @@
+use core::cell::RefCell;
+
+use defmt::Format;
+use embassy_rp::flash::{self, Blocking, Flash};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::{self, raw::NoopRawMutex};
+
+use crate::netlog;
+use crate::state::{self, FilterState, ValveStats, WifiCredentials};
+use crate::valve::MAX_VALVES;
+
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+pub type SharedFlash =
+    blocking_mutex::Mutex<NoopRawMutex, RefCell<Flash<'static, FLASH, Blocking, FLASH_SIZE>>>;
+
+// offset from the start of flash, must match STORAGE in memory.x
+const STORAGE_OFFSET: u32 = 0x1fc000;
+const SECTOR_SIZE: u32 = 4096;
+
+const RECORD_MAGIC: u32 = 0x5354_4f52;
+// magic, length, crc
+const RECORD_HEADER: usize = 10;
+const MAX_RECORD: usize = 256;
+
+// every slot owns one erase sector
+#[derive(Clone, Copy)]
+pub enum Slot {
+    ValveStats = 0,
+    // append only, see append_journal
+    Journal = 1,
+    WifiCredentials = 2,
+}
+
+// size of one valve entry: 12 bytes
+const VALVE_STATS_SIZE: usize = 12;
+
+const JOURNAL_MAGIC: u32 = 0x4a52_4e4c;
+// magic, state, time, elapsed, crc, padded to divide the sector
+const JOURNAL_ENTRY_SIZE: usize = 32;
+const JOURNAL_ENTRIES: u32 = SECTOR_SIZE / JOURNAL_ENTRY_SIZE as u32;
+
+// filter state and how long it had been running when the entry was written
+#[derive(Format, Clone, Copy)]
+pub struct JournalEntry {
+    pub filter_state: FilterState,
+    pub elapsed: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct Storage {
+    flash: &'static SharedFlash,
+}
+
+impl Storage {
+    pub const fn new(flash: &'static SharedFlash) -> Self {
+        Self { flash }
+    }
+
+    // returns the length of the record, None if the slot holds no valid record
+    fn read(&self, slot: Slot, data: &mut [u8]) -> Option<usize> {
+        let offset = STORAGE_OFFSET + slot as u32 * SECTOR_SIZE;
+        let mut header = [0; RECORD_HEADER];
+        self.flash
+            .lock(|f| f.borrow_mut().blocking_read(offset, &mut header))
+            .ok()?;
+
+        let magic = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let len = usize::from(u16::from_be_bytes([header[4], header[5]]));
+        let crc = u32::from_be_bytes([header[6], header[7], header[8], header[9]]);
+        if magic != RECORD_MAGIC || len > data.len() {
+            return None;
+        }
+
+        self.flash
+            .lock(|f| {
+                f.borrow_mut()
+                    .blocking_read(offset + RECORD_HEADER as u32, &mut data[..len])
+            })
+            .ok()?;
+        if crc32(&data[..len]) != crc {
+            netlog::warn!("storage record corrupted");
+            return None;
+        }
+
+        Some(len)
+    }
+
+    fn write(&self, slot: Slot, data: &[u8]) -> Result<(), flash::Error> {
+        let offset = STORAGE_OFFSET + slot as u32 * SECTOR_SIZE;
+        let mut buffer = [0xff; MAX_RECORD];
+        let len = data.len().min(MAX_RECORD - RECORD_HEADER);
+        buffer[0..4].copy_from_slice(&RECORD_MAGIC.to_be_bytes());
+        buffer[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+        buffer[6..10].copy_from_slice(&crc32(&data[..len]).to_be_bytes());
+        buffer[RECORD_HEADER..RECORD_HEADER + len].copy_from_slice(&data[..len]);
+
+        self.flash.lock(|f| {
+            let mut f = f.borrow_mut();
+            f.blocking_erase(offset, offset + SECTOR_SIZE)?;
+            f.blocking_write(offset, &buffer[..RECORD_HEADER + len])
+        })
+    }
+
+    pub fn load_valve_stats(&self) -> Option<[ValveStats; MAX_VALVES]> {
+        let mut data = [0; VALVE_STATS_SIZE * MAX_VALVES];
+        let len = self.read(Slot::ValveStats, &mut data)?;
+
+        let mut stats = [ValveStats::new(); MAX_VALVES];
+        for (stat, chunk) in stats
+            .iter_mut()
+            .zip(data[..len].chunks_exact(VALVE_STATS_SIZE))
+        {
+            stat.actuations = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            stat.open_time = u64::from_be_bytes([
+                chunk[4], chunk[5], chunk[6], chunk[7], chunk[8], chunk[9], chunk[10], chunk[11],
+            ]);
+        }
+        Some(stats)
+    }
+
+    pub fn store_valve_stats(&self, stats: &[ValveStats; MAX_VALVES]) -> Result<(), flash::Error> {
+        let mut data = [0; VALVE_STATS_SIZE * MAX_VALVES];
+        for (stat, chunk) in stats.iter().zip(data.chunks_exact_mut(VALVE_STATS_SIZE)) {
+            chunk[0..4].copy_from_slice(&stat.actuations.to_be_bytes());
+            chunk[4..12].copy_from_slice(&stat.open_time.to_be_bytes());
+        }
+        self.write(Slot::ValveStats, &data)
+    }
+
+    pub fn load_wifi_credentials(&self) -> Option<WifiCredentials> {
+        let mut data = [0; 2 + state::SSID_SIZE + state::PASSWORD_SIZE];
+        let len = self.read(Slot::WifiCredentials, &mut data)?;
+        let data = &data[..len];
+
+        let ssid_len = usize::from(*data.first()?);
+        let ssid = data.get(1..1 + ssid_len)?;
+        let password_len = usize::from(*data.get(1 + ssid_len)?);
+        let password = data.get(2 + ssid_len..2 + ssid_len + password_len)?;
+        WifiCredentials::new(
+            core::str::from_utf8(ssid).ok()?,
+            core::str::from_utf8(password).ok()?,
+        )
+    }
+
+    pub fn store_wifi_credentials(&self, credentials: &WifiCredentials) -> Result<(), flash::Error> {
+        let mut data = [0; 2 + state::SSID_SIZE + state::PASSWORD_SIZE];
+        let ssid = credentials.ssid().as_bytes();
+        let password = credentials.password().as_bytes();
+        data[0] = ssid.len() as u8;
+        data[1..1 + ssid.len()].copy_from_slice(ssid);
+        data[1 + ssid.len()] = password.len() as u8;
+        data[2 + ssid.len()..2 + ssid.len() + password.len()].copy_from_slice(password);
+        self.write(Slot::WifiCredentials, &data[..2 + ssid.len() + password.len()])
+    }
+
+    // newest intact journal entry, a torn write is skipped
+    pub fn load_journal(&self) -> Option<JournalEntry> {
+        let mut newest = None;
+        for index in 0..JOURNAL_ENTRIES {
+            match self.read_journal_entry(index) {
+                Some(Some(entry)) => newest = Some(entry),
+                Some(None) => netlog::warn!("journal entry {} corrupted", index),
+                // entries after the first blank one were never written
+                None => break,
+            }
+        }
+        newest
+    }
+
+    // entries are appended to the sector, it is only erased once it is full
+    pub fn append_journal(&self, entry: &JournalEntry) -> Result<(), flash::Error> {
+        let mut index = 0;
+        while index < JOURNAL_ENTRIES && self.read_journal_entry(index).is_some() {
+            index += 1;
+        }
+        let sector = STORAGE_OFFSET + Slot::Journal as u32 * SECTOR_SIZE;
+        if index == JOURNAL_ENTRIES {
+            self.flash
+                .lock(|f| f.borrow_mut().blocking_erase(sector, sector + SECTOR_SIZE))?;
+            index = 0;
+        }
+
+        let (state, time) = match entry.filter_state {
+            FilterState::Idle => (0x00, 0),
+            FilterState::CleanBeforeFill => (0x01, 0),
+            FilterState::CleanAfterFill => (0x02, 0),
+            FilterState::Fill => (0x03, 0),
+            FilterState::ForcedFill(time) => (0x04, time),
+            FilterState::ForcedClean(time) => (0x05, time),
+            FilterState::ForcedIdle(time) => (0x06, time),
+        };
+        let mut buffer = [0; JOURNAL_ENTRY_SIZE];
+        buffer[0..4].copy_from_slice(&JOURNAL_MAGIC.to_be_bytes());
+        buffer[4] = state;
+        buffer[8..16].copy_from_slice(&time.to_be_bytes());
+        buffer[16..24].copy_from_slice(&entry.elapsed.to_be_bytes());
+        let crc = crc32(&buffer[..24]);
+        buffer[24..28].copy_from_slice(&crc.to_be_bytes());
+
+        let offset = sector + index * JOURNAL_ENTRY_SIZE as u32;
+        self.flash
+            .lock(|f| f.borrow_mut().blocking_write(offset, &buffer))
+    }
+
+    // None if the entry is blank, Some(None) if it is corrupted
+    fn read_journal_entry(&self, index: u32) -> Option<Option<JournalEntry>> {
+        let offset = STORAGE_OFFSET
+            + Slot::Journal as u32 * SECTOR_SIZE
+            + index * JOURNAL_ENTRY_SIZE as u32;
+        let mut buffer = [0; JOURNAL_ENTRY_SIZE];
+        self.flash
+            .lock(|f| f.borrow_mut().blocking_read(offset, &mut buffer))
+            .ok()?;
+
+        let magic = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+        if magic == 0xffff_ffff {
+            return None;
+        }
+        let crc = u32::from_be_bytes([buffer[24], buffer[25], buffer[26], buffer[27]]);
+        if magic != JOURNAL_MAGIC || crc32(&buffer[..24]) != crc {
+            return Some(None);
+        }
+
+        let time = u64::from_be_bytes([
+            buffer[8], buffer[9], buffer[10], buffer[11], buffer[12], buffer[13], buffer[14],
+            buffer[15],
+        ]);
+        let elapsed = u64::from_be_bytes([
+            buffer[16], buffer[17], buffer[18], buffer[19], buffer[20], buffer[21], buffer[22],
+            buffer[23],
+        ]);
+        let filter_state = match buffer[4] {
+            0x01 => FilterState::CleanBeforeFill,
+            0x02 => FilterState::CleanAfterFill,
+            0x03 => FilterState::Fill,
+            0x04 => FilterState::ForcedFill(time),
+            0x05 => FilterState::ForcedClean(time),
+            0x06 => FilterState::ForcedIdle(time),
+            _ => FilterState::Idle,
+        };
+        Some(Some(JournalEntry {
+            filter_state,
+            elapsed,
+        }))
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xedb8_8320;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    !crc
+}
@@ -0,0 +1,74 @@
+use core::cell::Cell;
+
+use defmt::Format;
+use embassy_rp::watchdog::Watchdog;
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::netlog;
+use crate::reset;
+use crate::state::ResetReason;
+
+// the bootloader already started the watchdog with the same timeout
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(8);
+
+const TASK_COUNT: usize = 3;
+
+// tasks that have to check in for the watchdog to be fed
+#[derive(Format, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Task {
+    Measure = 0,
+    State = 1,
+    Network = 2,
+}
+
+pub const TASKS: [Task; TASK_COUNT] = [Task::Measure, Task::State, Task::Network];
+
+impl Task {
+    // longest time the task may go without checking in
+    const fn deadline(self) -> Duration {
+        match self {
+            // one measurement burst every 5s
+            Task::Measure => Duration::from_secs(30),
+            // a valve exercise holds the task for a while
+            Task::State => Duration::from_secs(60),
+            // joining the network and connecting can take long
+            Task::Network => Duration::from_secs(180),
+        }
+    }
+}
+
+// time of the last check in per task in ms, boot counts as the first one
+static CHECK_INS: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<[u64; TASK_COUNT]>> =
+    blocking_mutex::Mutex::new(Cell::new([0; TASK_COUNT]));
+
+pub fn check_in(task: Task) {
+    let now = Instant::now().as_millis();
+    CHECK_INS.lock(|c| {
+        let mut check_ins = c.get();
+        check_ins[task as usize] = now;
+        c.set(check_ins);
+    });
+}
+
+// feeds the watchdog while all tasks are alive, resets with the starved task recorded otherwise.
+// if the executor itself hangs the watchdog resets without a record
+#[embassy_executor::task]
+pub async fn supervisor_task(mut watchdog: Watchdog) -> ! {
+    watchdog.start(WATCHDOG_TIMEOUT);
+    loop {
+        let now = Instant::now().as_millis();
+        let check_ins = CHECK_INS.lock(Cell::get);
+        if let Some(task) = TASKS
+            .into_iter()
+            .find(|t| now - check_ins[*t as usize] > t.deadline().as_millis())
+        {
+            netlog::error!("task {:?} starved, resetting", task);
+            reset::record(ResetReason::Watchdog(task));
+            watchdog.trigger_reset();
+        }
+
+        watchdog.feed();
+        Timer::after(WATCHDOG_TIMEOUT / 4).await;
+    }
+}
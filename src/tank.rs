@@ -0,0 +1,64 @@
+use crate::state::{TankConfig, TankShape};
+
+// height of the water above the tank bottom in mm
+pub fn fill_height(tank: &TankConfig, distance: u64) -> u64 {
+    tank.sensor_height.saturating_sub(distance)
+}
+
+pub fn volume_ml(tank: &TankConfig, height: u64) -> u64 {
+    match tank.shape {
+        // pi approximated by 355 / 113
+        TankShape::Cylinder { diameter } => diameter * diameter * height * 355 / (113 * 4 * 1000),
+        TankShape::Rectangle { width, length } => width * length * height / 1000,
+        TankShape::Table(points) => table_volume(points, height),
+    }
+}
+
+// percent of the volume at full_height
+pub fn percent(tank: &TankConfig, height: u64) -> u64 {
+    volume_ml(tank, height) * 100 / volume_ml(tank, tank.full_height).max(1)
+}
+
+// distance from the sensor at which the tank is filled to percent
+pub fn distance_for_percent(tank: &TankConfig, percent: u64) -> u64 {
+    let height = match tank.shape {
+        TankShape::Table(points) => {
+            table_height(points, volume_ml(tank, tank.full_height) * percent / 100)
+        }
+        // volume grows linear with height
+        _ => tank.full_height * percent / 100,
+    };
+    tank.sensor_height.saturating_sub(height)
+}
+
+fn lerp(x: u64, (x0, y0): (u64, u64), (x1, y1): (u64, u64)) -> u64 {
+    if x1 <= x0 {
+        return y1;
+    }
+    y0 + y1.saturating_sub(y0) * (x - x0) / (x1 - x0)
+}
+
+// table points are (height in mm, volume in l) sorted by height
+fn table_volume(points: &[(u64, u64)], height: u64) -> u64 {
+    let mut prev = (0, 0);
+    for &(h, litres) in points {
+        let point = (h, litres * 1000);
+        if height <= point.0 {
+            return lerp(height, prev, point);
+        }
+        prev = point;
+    }
+    prev.1
+}
+
+fn table_height(points: &[(u64, u64)], volume_ml: u64) -> u64 {
+    let mut prev = (0, 0);
+    for &(h, litres) in points {
+        let point = (litres * 1000, h);
+        if volume_ml <= point.0 {
+            return lerp(volume_ml, prev, point);
+        }
+        prev = point;
+    }
+    prev.1
+}
@@ -0,0 +1,178 @@
+use defmt::Format;
+use embassy_rp::adc::{self, Adc, Channel};
+use embassy_rp::gpio::{AnyPin, Flex};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{block_for, Duration, Timer};
+
+use crate::netlog;
+use crate::STATE;
+
+pub type SharedAdc = Mutex<CriticalSectionRawMutex, Adc<'static, adc::Async>>;
+
+#[derive(Format, PartialEq, Eq, Clone, Copy)]
+pub enum SensorKind {
+    Internal,
+    Ds18b20,
+}
+
+pub enum TemperatureSensor {
+    Internal(&'static SharedAdc, Channel<'static>),
+    Ds18b20(OneWire),
+}
+
+impl TemperatureSensor {
+    // temperature in 0.01 °C
+    pub async fn read(&mut self) -> Option<i32> {
+        match self {
+            Self::Internal(adc, channel) => {
+                let raw = adc.lock().await.read(channel).await.ok()?;
+                // 12 bit reading against 3.3V reference
+                let microvolts = i64::from(raw) * 3_300_000 / 4096;
+                // datasheet: 27 °C at 0.706V, -1.721 mV per °C
+                Some((2700 - (microvolts - 706_000) * 100 / 1721) as i32)
+            }
+            Self::Ds18b20(bus) => read_ds18b20(bus).await,
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn temperature_task(mut sensor: TemperatureSensor) -> ! {
+    loop {
+        let temperature = sensor.read().await;
+        if temperature.is_none() {
+            netlog::warn!("temperature reading failed");
+        }
+        STATE.lock().await.state.temperature = temperature;
+
+        Timer::after(Duration::from_secs(30)).await;
+    }
+}
+
+// half the speed of sound in mm/s, the echo travels the distance twice
+pub fn half_speed_of_sound(temperature: Option<i32>) -> u64 {
+    match temperature {
+        // 331.3 m/s at 0 °C plus 0.606 m/s per °C
+        Some(t) => (33_130_000 + 606 * i64::from(t.clamp(-4000, 8500))) as u64 / 200,
+        None => 171_605,
+    }
+}
+
+const SKIP_ROM: u8 = 0xcc;
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xbe;
+
+async fn read_ds18b20(bus: &mut OneWire) -> Option<i32> {
+    if !bus.reset() {
+        return None;
+    }
+    bus.write_byte(SKIP_ROM);
+    bus.write_byte(CONVERT_T);
+
+    // max conversion time at 12 bit resolution
+    Timer::after(Duration::from_millis(750)).await;
+
+    if !bus.reset() {
+        return None;
+    }
+    bus.write_byte(SKIP_ROM);
+    bus.write_byte(READ_SCRATCHPAD);
+
+    let mut scratchpad = [0; 9];
+    for byte in scratchpad.iter_mut() {
+        *byte = bus.read_byte();
+    }
+    if crc8(&scratchpad[0..8]) != scratchpad[8] {
+        netlog::warn!("ds18b20 crc mismatch");
+        return None;
+    }
+
+    // 1/16 °C per bit
+    let raw = i16::from_le_bytes([scratchpad[0], scratchpad[1]]);
+    Some(i32::from(raw) * 100 / 16)
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0;
+    for byte in data {
+        let mut byte = *byte;
+        for _ in 0..8 {
+            let mix = (crc ^ byte) & 0x01;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8c;
+            }
+            byte >>= 1;
+        }
+    }
+    crc
+}
+
+// bit-banged 1-Wire bus, needs an external pull-up
+pub struct OneWire {
+    pin: Flex<'static, AnyPin>,
+}
+
+impl OneWire {
+    pub fn new(mut pin: Flex<'static, AnyPin>) -> Self {
+        // the bus is only ever pulled low, releasing it means switching to input
+        pin.set_low();
+        pin.set_as_input();
+        Self { pin }
+    }
+
+    fn reset(&mut self) -> bool {
+        self.pin.set_as_output();
+        block_for(Duration::from_micros(480));
+        let present = cortex_m::interrupt::free(|_| {
+            self.pin.set_as_input();
+            block_for(Duration::from_micros(70));
+            self.pin.is_low()
+        });
+        block_for(Duration::from_micros(410));
+        present
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        cortex_m::interrupt::free(|_| {
+            self.pin.set_as_output();
+            if bit {
+                block_for(Duration::from_micros(6));
+                self.pin.set_as_input();
+                block_for(Duration::from_micros(64));
+            } else {
+                block_for(Duration::from_micros(60));
+                self.pin.set_as_input();
+                block_for(Duration::from_micros(10));
+            }
+        });
+    }
+
+    fn read_bit(&mut self) -> bool {
+        cortex_m::interrupt::free(|_| {
+            self.pin.set_as_output();
+            block_for(Duration::from_micros(6));
+            self.pin.set_as_input();
+            block_for(Duration::from_micros(9));
+            let bit = self.pin.is_high();
+            block_for(Duration::from_micros(55));
+            bit
+        })
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        for i in 0..8 {
+            self.write_bit(byte & (1 << i) != 0);
+        }
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = 0;
+        for i in 0..8 {
+            if self.read_bit() {
+                byte |= 1 << i;
+            }
+        }
+        byte
+    }
+}
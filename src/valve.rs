@@ -1,4 +1,17 @@
-use embassy_rp::gpio::{Output, Pin};
+use core::cell::RefCell;
+
+use embassy_rp::adc::Channel;
+use embassy_rp::gpio::{AnyPin, Input, Output, Pin};
+use embassy_rp::peripherals::{PWM_CH6, PWM_CH7};
+use embassy_rp::pwm::{self, Pwm};
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Timer};
+
+use crate::state::{ValveConfig, ValveFault, ValveStats};
+use crate::temperature::SharedAdc;
+
+// valve patterns are bitmasks, so at most 8 valves
+pub const MAX_VALVES: usize = 8;
 
 pub struct Valve<T: Pin> {
     pin: Output<'static, T>,
@@ -18,51 +31,317 @@ impl<T: Pin> Valve<T> {
     }
 }
 
-pub struct ValveControler {
-    valve1: Valve<embassy_rp::peripherals::PIN_12>,
-    valve2: Valve<embassy_rp::peripherals::PIN_13>,
-    valve3: Valve<embassy_rp::peripherals::PIN_14>,
-    valve4: Valve<embassy_rp::peripherals::PIN_15>,
+// bistable valve behind an h-bridge, only needs current while switching
+pub struct LatchingValve {
+    open_pin: Output<'static, AnyPin>,
+    close_pin: Output<'static, AnyPin>,
+    pulse: Duration,
+    // the pins are idle between pulses, so only the last command tells the position
+    position: Option<bool>,
 }
 
-impl ValveControler {
+impl LatchingValve {
     pub fn new(
-        mut valve1: Valve<embassy_rp::peripherals::PIN_12>,
-        mut valve2: Valve<embassy_rp::peripherals::PIN_13>,
-        mut valve3: Valve<embassy_rp::peripherals::PIN_14>,
-        mut valve4: Valve<embassy_rp::peripherals::PIN_15>,
+        mut open_pin: Output<'static, AnyPin>,
+        mut close_pin: Output<'static, AnyPin>,
+        pulse: Duration,
     ) -> Self {
-        valve1.close();
-        valve2.close();
-        valve3.close();
-        valve4.close();
+        open_pin.set_low();
+        close_pin.set_low();
 
         Self {
-            valve1,
-            valve2,
-            valve3,
-            valve4,
+            open_pin,
+            close_pin,
+            pulse,
+            position: None,
         }
     }
 
-    pub fn clean(&mut self) {
-        self.valve1.open();
-        self.valve2.open();
-        self.valve3.open();
-        self.valve4.close();
+    async fn drive(&mut self, open: bool) {
+        if self.position == Some(open) {
+            return;
+        }
+        let pin = if open {
+            &mut self.open_pin
+        } else {
+            &mut self.close_pin
+        };
+        pin.set_high();
+        Timer::after(self.pulse).await;
+        pin.set_low();
+        self.position = Some(open);
     }
+}
+
+// slices of the valve pins, PIN_12/PIN_13 are slice 6 and PIN_14/PIN_15 slice 7
+pub enum PwmSlice {
+    Slice6(Pwm<'static, PWM_CH6>),
+    Slice7(Pwm<'static, PWM_CH7>),
+}
+
+#[derive(Clone, Copy)]
+pub enum PwmOutput {
+    A,
+    B,
+}
+
+// both outputs of a slice share one config, so the slice is shared between two valves
+pub struct PwmPair {
+    slice: PwmSlice,
+    config: pwm::Config,
+}
+
+pub type SharedPwmPair = blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<PwmPair>>;
+
+impl PwmPair {
+    pub const fn new(slice: PwmSlice, config: pwm::Config) -> Self {
+        Self { slice, config }
+    }
+
+    // 20kHz at 125MHz, above the audible range
+    pub fn config() -> pwm::Config {
+        let mut config = pwm::Config::default();
+        config.top = 6249;
+        config.compare_a = 0;
+        config.compare_b = 0;
+        config
+    }
+
+    fn set_duty(&mut self, output: PwmOutput, percent: u8) {
+        let compare = (u32::from(self.config.top) + 1) * u32::from(percent.min(100)) / 100;
+        let compare = compare as u16;
+        match output {
+            PwmOutput::A => self.config.compare_a = compare,
+            PwmOutput::B => self.config.compare_b = compare,
+        }
+        match &mut self.slice {
+            PwmSlice::Slice6(pwm) => pwm.set_config(&self.config),
+            PwmSlice::Slice7(pwm) => pwm.set_config(&self.config),
+        }
+    }
+}
+
+// monostable valve that drops to a lower hold current after pulling in
+pub struct PwmValve {
+    pair: &'static SharedPwmPair,
+    output: PwmOutput,
+}
 
-    pub fn fill(&mut self) {
-        self.valve1.open();
-        self.valve2.open();
-        self.valve3.close();
-        self.valve4.open();
+impl PwmValve {
+    pub const fn new(pair: &'static SharedPwmPair, output: PwmOutput) -> Self {
+        Self { pair, output }
     }
 
-    pub fn idle(&mut self) {
-        self.valve1.close();
-        self.valve2.close();
-        self.valve3.close();
-        self.valve4.close();
+    fn set_duty(&mut self, percent: u8) {
+        self.pair
+            .lock(|pair| pair.borrow_mut().set_duty(self.output, percent));
+    }
+
+    async fn open(&mut self, config: &ValveConfig) {
+        self.set_duty(100);
+        Timer::after(Duration::from_millis(config.pull_in)).await;
+        self.set_duty(config.hold_duty);
+    }
+
+    fn close(&mut self) {
+        self.set_duty(0);
+    }
+}
+
+pub enum ValveDriver {
+    Monostable(Valve<AnyPin>),
+    Latching(LatchingValve),
+    Pwm(PwmValve),
+}
+
+impl ValveDriver {
+    async fn open(&mut self, config: &ValveConfig) {
+        match self {
+            Self::Monostable(valve) => valve.open(),
+            Self::Latching(valve) => valve.drive(true).await,
+            Self::Pwm(valve) => valve.open(config).await,
+        }
+    }
+
+    async fn close(&mut self) {
+        match self {
+            Self::Monostable(valve) => valve.close(),
+            Self::Latching(valve) => valve.drive(false).await,
+            Self::Pwm(valve) => valve.close(),
+        }
+    }
+}
+
+pub enum Feedback {
+    None,
+    // closes to ground when the valve is open
+    LimitSwitch(Input<'static, AnyPin>),
+    // coil current over a shunt, open above the threshold. latching valves
+    // draw no current while resting, so this only works for held valves
+    CurrentSense(&'static SharedAdc, Channel<'static>, u16),
+}
+
+impl Feedback {
+    // None if the position can't be sensed
+    async fn is_open(&mut self) -> Option<bool> {
+        match self {
+            Self::None => None,
+            Self::LimitSwitch(input) => Some(input.is_low()),
+            Self::CurrentSense(adc, channel, threshold) => {
+                let raw = adc.lock().await.read(channel).await.ok()?;
+                Some(raw > *threshold)
+            }
+        }
+    }
+}
+
+pub struct ValveControler<const N: usize> {
+    valves: [ValveDriver; N],
+    feedback: [Feedback; N],
+    // bit i set if valve i is open
+    open: u8,
+    stats: [ValveStats; N],
+    // time the open time was last added to stats
+    counted_until: [u64; N],
+}
+
+impl<const N: usize> ValveControler<N> {
+    // the position of latching valves is unknown until close_all ran
+    pub const fn new(valves: [ValveDriver; N], feedback: [Feedback; N]) -> Self {
+        Self {
+            valves,
+            feedback,
+            open: 0,
+            stats: [ValveStats::new(); N],
+            counted_until: [0; N],
+        }
+    }
+
+    pub fn restore_stats(&mut self, stats: &[ValveStats]) {
+        for (own, stored) in self.stats.iter_mut().zip(stats) {
+            *own = *stored;
+        }
+    }
+
+    // includes the time of valves that are still open
+    pub fn stats(&mut self) -> [ValveStats; N] {
+        let now = embassy_time::Instant::now().as_millis();
+        for i in 0..N {
+            if self.open & (1 << i) != 0 {
+                self.stats[i].open_time += now - self.counted_until[i];
+                self.counted_until[i] = now;
+            }
+        }
+        self.stats
+    }
+
+    fn target(mask: u8, config: &ValveConfig) -> u8 {
+        let count = usize::from(config.count).min(N);
+        (0..count).fold(0, |m, i| m | (mask & (1 << i)))
+    }
+
+    // true if switching to mask would move any valve
+    pub fn changes(&self, mask: u8, config: &ValveConfig) -> bool {
+        Self::target(mask, config) != self.open
+    }
+
+    // true if at least one valve is open
+    pub const fn is_open(&self) -> bool {
+        self.open != 0
+    }
+
+    // bit i of mask opens valve i, valves beyond the configured count stay closed.
+    // valves are closed first, then opened one at a time, so no unintended path
+    // opens during the transition and the solenoids don't draw inrush current together
+    async fn set(&mut self, mask: u8, config: &ValveConfig) -> Result<(), ValveFault> {
+        let target = Self::target(mask, config);
+        if target == self.open {
+            return Ok(());
+        }
+
+        let closing = self.open & !target;
+        let opening = target & !self.open;
+
+        let now = embassy_time::Instant::now().as_millis();
+        for (i, valve) in self.valves.iter_mut().enumerate() {
+            if closing & (1 << i) != 0 {
+                valve.close().await;
+                self.stats[i].open_time += now - self.counted_until[i];
+            }
+        }
+        self.open &= !closing;
+
+        if closing != 0 && opening != 0 {
+            Timer::after(Duration::from_millis(config.dead_time)).await;
+        }
+
+        let mut first = true;
+        for (i, valve) in self.valves.iter_mut().enumerate() {
+            if opening & (1 << i) == 0 {
+                continue;
+            }
+            if !first {
+                Timer::after(Duration::from_millis(config.stagger)).await;
+            }
+            first = false;
+            valve.open(config).await;
+            self.open |= 1 << i;
+            self.stats[i].actuations += 1;
+            self.counted_until[i] = embassy_time::Instant::now().as_millis();
+        }
+
+        // give the valves time to move before checking
+        Timer::after(Duration::from_millis(config.feedback_delay)).await;
+        self.check().await
+    }
+
+    // compare the sensed positions with the commanded ones
+    async fn check(&mut self) -> Result<(), ValveFault> {
+        for (i, feedback) in self.feedback.iter_mut().enumerate() {
+            let expected_open = self.open & (1 << i) != 0;
+            match feedback.is_open().await {
+                Some(open) if open != expected_open => {
+                    return Err(ValveFault {
+                        valve: i as u8,
+                        expected_open,
+                    })
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn clean(&mut self, config: &ValveConfig) -> Result<(), ValveFault> {
+        self.set(config.clean, config).await
+    }
+
+    pub async fn fill(&mut self, config: &ValveConfig) -> Result<(), ValveFault> {
+        self.set(config.fill, config).await
+    }
+
+    pub async fn idle(&mut self, config: &ValveConfig) -> Result<(), ValveFault> {
+        self.set(config.idle, config).await
+    }
+
+    // opens a single valve and closes all others
+    pub async fn open_only(&mut self, valve: u8, config: &ValveConfig) -> Result<(), ValveFault> {
+        self.set(1 << valve, config).await
+    }
+
+    pub async fn close(&mut self, config: &ValveConfig) -> Result<(), ValveFault> {
+        self.set(0, config).await
+    }
+
+    // ignores the configured idle pattern, used when a fault requires a safe state
+    pub async fn close_all(&mut self) {
+        let now = embassy_time::Instant::now().as_millis();
+        for (i, valve) in self.valves.iter_mut().enumerate() {
+            valve.close().await;
+            if self.open & (1 << i) != 0 {
+                self.stats[i].open_time += now - self.counted_until[i];
+            }
+        }
+        self.open = 0;
     }
 }
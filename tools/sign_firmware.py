@@ -0,0 +1,106 @@
+#!/usr/bin/env python3
+"""Create the release key and sign firmware images for the OTA update.
+
+    sign_firmware.py keygen [name]
+        writes name.key (private, keep it safe) and name.pub, which the
+        firmware embeds at build time. name defaults to firmware_key.
+
+    sign_firmware.py sign <elf> <key> [out]
+        converts the ELF to the flat image that is written to the update
+        partition and signs the sha512 digest of it. Writes out.bin and
+        out.sig (64 bytes) and prints the values of the load new firmware
+        command. out defaults to the ELF path without extension.
+
+Requires the `cryptography` package.
+"""
+
+import hashlib
+import os
+import struct
+import sys
+
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
+from cryptography.hazmat.primitives.serialization import Encoding, PublicFormat
+
+# FLASH in memory.x
+ACTIVE_START = 0x10007000
+ACTIVE_SIZE = 960 * 1024
+
+PT_LOAD = 1
+
+
+def keygen(name):
+    if os.path.exists(name + ".key"):
+        sys.exit(f"{name}.key exists, not overwriting it")
+    key = Ed25519PrivateKey.generate()
+    with open(name + ".key", "wb") as f:
+        f.write(key.private_bytes_raw())
+    os.chmod(name + ".key", 0o600)
+    with open(name + ".pub", "wb") as f:
+        f.write(key.public_key().public_bytes(Encoding.Raw, PublicFormat.Raw))
+    print(f"wrote {name}.key and {name}.pub")
+
+
+# flat image of all loadable segments in the active partition, gaps are erased flash
+def elf_to_image(path):
+    with open(path, "rb") as f:
+        elf = f.read()
+    if elf[:4] != b"\x7fELF" or elf[4] != 1 or elf[5] != 1:
+        sys.exit(f"{path} is not a 32 bit little endian ELF")
+
+    phoff, = struct.unpack_from("<I", elf, 0x1C)
+    phentsize, phnum = struct.unpack_from("<HH", elf, 0x2A)
+
+    image = bytearray()
+    for i in range(phnum):
+        p_type, p_offset, _vaddr, p_paddr, p_filesz = struct.unpack_from(
+            "<IIIII", elf, phoff + i * phentsize
+        )
+        if p_type != PT_LOAD or p_filesz == 0:
+            continue
+        # BOOT2 is not part of the update
+        if not ACTIVE_START <= p_paddr < ACTIVE_START + ACTIVE_SIZE:
+            continue
+        start = p_paddr - ACTIVE_START
+        end = start + p_filesz
+        if end > ACTIVE_SIZE:
+            sys.exit("image does not fit into the active partition")
+        if len(image) < end:
+            image.extend(b"\xff" * (end - len(image)))
+        image[start:end] = elf[p_offset : p_offset + p_filesz]
+
+    if not image:
+        sys.exit(f"{path} has no segments in the active partition")
+    return bytes(image)
+
+
+def sign(elf, key_path, out):
+    with open(key_path, "rb") as f:
+        key = Ed25519PrivateKey.from_private_bytes(f.read())
+
+    image = elf_to_image(elf)
+    # the bootloader verifies the signature over the sha512 digest
+    signature = key.sign(hashlib.sha512(image).digest())
+
+    with open(out + ".bin", "wb") as f:
+        f.write(image)
+    with open(out + ".sig", "wb") as f:
+        f.write(signature)
+
+    print(f"firmware_size: {len(image)}")
+    print(f"sha256:        {hashlib.sha256(image).hexdigest()}")
+    print(f"signature:     {signature.hex()}")
+
+
+def main(args):
+    if len(args) in (1, 2) and args[0] == "keygen":
+        keygen(args[1] if len(args) == 2 else "firmware_key")
+    elif len(args) in (3, 4) and args[0] == "sign":
+        out = args[3] if len(args) == 4 else os.path.splitext(args[1])[0]
+        sign(args[1], args[2], out)
+    else:
+        sys.exit(__doc__)
+
+
+if __name__ == "__main__":
+    main(sys.argv[1:])